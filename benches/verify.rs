@@ -0,0 +1,52 @@
+//! What checking one interaction's signature costs, the way `handle_request`
+//! used to do it and with a `Verifier` made at startup.
+//!
+//! `cargo bench --bench verify`
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+
+use magic::verify::Verifier;
+
+const TIMESTAMP: &str = "1610000000";
+const BODY: &str = r#"{"id":"800000000000000000","type":2,"data":{"id":"796995810038382642","name":"create"},"guild_id":"1","channel_id":"2","member":{"user":{"id":"3"},"permissions":"0"},"token":"t","version":1}"#;
+
+fn signed() -> (String, Vec<u8>) {
+    let key = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).expect("bad seed");
+    let signature = key.sign(format!("{}{}", TIMESTAMP, BODY).as_bytes());
+
+    (
+        hex::encode(key.public_key().as_ref()),
+        signature.as_ref().to_vec(),
+    )
+}
+
+fn per_request(c: &mut Criterion) {
+    let (public_key, signature) = signed();
+
+    // the key came in as hex and got decoded and wrapped for every request
+    c.bench_function("decode the key every request", |b| {
+        b.iter(|| {
+            let key = signature::UnparsedPublicKey::new(
+                &signature::ED25519,
+                hex::decode(black_box(&public_key)).expect("bad key"),
+            );
+
+            let mut message: Vec<u8> = TIMESTAMP.into();
+            message.extend_from_slice(black_box(BODY).as_bytes());
+            assert!(key.verify(&message, &signature).is_ok());
+        });
+    });
+
+    let mut verifier = Verifier::default();
+    verifier.add_key(&public_key).expect("bad key");
+
+    c.bench_function("shared verifier", |b| {
+        b.iter(|| {
+            assert!(verifier.verify(TIMESTAMP.as_bytes(), black_box(BODY).as_bytes(), &signature));
+        });
+    });
+}
+
+criterion_group!(benches, per_request);
+criterion_main!(benches);
@@ -0,0 +1,13 @@
+// not registered yet, so this is matched by name instead of id
+let payload = {
+	"name": "game",
+	"description": "see everything that happened in a game",
+	"options": [
+		{
+			"type": 3,
+			"name": "id",
+			"description": "which game (/history lists them)",
+			"required": true
+		}
+	]
+}
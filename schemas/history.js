@@ -0,0 +1,5 @@
+// not registered yet, so this is matched by name instead of id
+let payload = {
+	"name": "history",
+	"description": "see the last few games played in this channel"
+}
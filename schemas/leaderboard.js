@@ -0,0 +1,22 @@
+// not registered yet, so this is matched by name instead of id
+let payload = {
+	"name": "leaderboard",
+	"description": "see who's doing best in this server",
+	"options": [
+		{
+			"type": 3,
+			"name": "category",
+			"description": "what to rank by (defaults to wins)",
+			"choices": [
+				{ "name": "most wins", "value": "wins" },
+				{ "name": "best win rate", "value": "win-rate" },
+				{ "name": "best magician", "value": "magician" }
+			]
+		},
+		{
+			"type": 4,
+			"name": "season",
+			"description": "which season to show (defaults to the current one)"
+		}
+	]
+}
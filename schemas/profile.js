@@ -0,0 +1,12 @@
+// not registered yet, so this is matched by name instead of id
+let payload = {
+	"name": "profile",
+	"description": "see someone's achievements",
+	"options": [
+		{
+			"type": 6,
+			"name": "player",
+			"description": "whose profile to show (defaults to you)"
+		}
+	]
+}
@@ -0,0 +1,13 @@
+// not registered yet, so this is matched by name instead of id
+let payload = {
+	"name": "read",
+	"description": "as the oracle, find out whether someone is the magician",
+	"options": [
+		{
+			"type": 6,
+			"name": "player",
+			"description": "who to read",
+			"required": true
+		}
+	]
+}
@@ -0,0 +1,5 @@
+// not registered yet, so this is matched by name instead of id
+let payload = {
+	"name": "role",
+	"description": "see what you are in this game (only you see the answer)"
+}
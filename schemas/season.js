@@ -0,0 +1,12 @@
+// not registered yet, so this is matched by name instead of id
+let payload = {
+	"name": "season",
+	"description": "see the current leaderboard season, or start a new one",
+	"options": [
+		{
+			"type": 5,
+			"name": "start-new",
+			"description": "archive the current rankings and start fresh (needs Manage Server)"
+		}
+	]
+}
@@ -0,0 +1,12 @@
+// not registered yet, so this is matched by name instead of id
+let payload = {
+	"name": "settings",
+	"description": "see or change how the bot behaves in this server",
+	"options": [
+		{
+			"type": 4,
+			"name": "lobby-timeout",
+			"description": "minutes a lobby can sit idle before it's closed (needs Manage Server)"
+		}
+	]
+}
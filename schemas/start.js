@@ -0,0 +1,5 @@
+// not registered yet, so this is matched by name instead of id
+let payload = {
+	"name": "start",
+	"description": "hand out roles and start the game (only the lobby's creator can)"
+}
@@ -0,0 +1,12 @@
+// not registered yet, so this is matched by name instead of id
+let payload = {
+	"name": "stats",
+	"description": "see how someone's games have gone",
+	"options": [
+		{
+			"type": 6,
+			"name": "player",
+			"description": "whose stats to show (defaults to you)"
+		}
+	]
+}
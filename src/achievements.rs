@@ -0,0 +1,286 @@
+//! Achievements are plain data: each one is a `Condition` checked against how
+//! a player's game went once it's over. Adding one means adding an entry to
+//! `ALL`, and nothing else.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::encoding::{Upgrade, Versioned};
+use crate::game::{GameResult, PlayerResult, Role};
+use crate::history::{Event, LoggedEvent};
+use crate::{Database, MagicError};
+
+pub const TREE: &str = "achievements";
+
+pub struct Achievement {
+    /// What it's stored as, so never change these.
+    pub id: &'static str,
+    pub name: &'static str,
+    pub description: &'static str,
+    pub condition: Condition,
+}
+
+/// Something that was true about one player's game.
+pub enum Condition {
+    Won,
+    Played(Role),
+    /// Banished by the vote.
+    Banished,
+    /// At least this many correct oracle reads.
+    CorrectReads(u64),
+    /// Experimented on at least this many times.
+    ExperimentedOn(u64),
+    /// Voted for by other players at most this many times.
+    VotedForAtMost(u64),
+    /// Voted for whoever ended up banished.
+    VotedForBanished,
+    All(&'static [Condition]),
+    Any(&'static [Condition]),
+    Not(&'static Condition),
+}
+
+pub const ALL: &[Achievement] = &[
+    Achievement {
+        id: "first-win",
+        name: "beginner's luck",
+        description: "win a game",
+        condition: Condition::Won,
+    },
+    Achievement {
+        id: "untouchable-magician",
+        name: "above suspicion",
+        description: "win as the magician without being voted for once",
+        condition: Condition::All(&[
+            Condition::Won,
+            Condition::Played(Role::Magician),
+            Condition::VotedForAtMost(0),
+        ]),
+    },
+    Achievement {
+        id: "three-reads",
+        name: "second sight",
+        description: "make three correct oracle reads in one game",
+        condition: Condition::CorrectReads(3),
+    },
+    Achievement {
+        id: "lab-rat",
+        name: "lab rat",
+        description: "get experimented on three times in one game and live to win it",
+        condition: Condition::All(&[
+            Condition::Won,
+            Condition::Not(&Condition::Banished),
+            Condition::ExperimentedOn(3),
+        ]),
+    },
+    Achievement {
+        id: "good-instincts",
+        name: "good instincts",
+        description: "vote for the player who gets banished",
+        condition: Condition::VotedForBanished,
+    },
+    Achievement {
+        id: "martyr",
+        name: "martyr",
+        description: "get banished as a villager or the oracle, and win anyway",
+        condition: Condition::All(&[
+            Condition::Won,
+            Condition::Banished,
+            Condition::Any(&[
+                Condition::Played(Role::Villager),
+                Condition::Played(Role::Oracle),
+            ]),
+        ]),
+    },
+];
+
+/// One player's game, for checking conditions against.
+pub struct PlayerGame<'a> {
+    pub player: &'a PlayerResult,
+    pub result: &'a GameResult,
+    /// `None` if the game's events weren't kept, in which case nothing that
+    /// depends on them holds.
+    pub events: Option<&'a [LoggedEvent]>,
+}
+
+impl PlayerGame<'_> {
+    fn votes_against(&self) -> Option<u64> {
+        let votes = self
+            .events?
+            .iter()
+            .filter(|logged| {
+                matches!(&logged.event, Event::Voted { voter, target }
+                    if target == &self.player.user_id && voter != &self.player.user_id)
+            })
+            .count();
+
+        Some(votes as u64)
+    }
+
+    fn voted_for_banished(&self) -> Option<bool> {
+        let voted = self.events?.iter().any(|logged| match &logged.event {
+            Event::Voted { voter, target } if voter == &self.player.user_id => self
+                .result
+                .players
+                .iter()
+                .any(|other| &other.user_id == target && other.banished),
+            _ => false,
+        });
+
+        Some(voted)
+    }
+}
+
+impl Condition {
+    /// Conditions about events don't hold without them, and neither does
+    /// `Not` one of them, since we can't tell either way.
+    pub fn holds(&self, game: &PlayerGame) -> bool {
+        self.check(game).unwrap_or(false)
+    }
+
+    /// Whether this holds, if that can be known.
+    fn check(&self, game: &PlayerGame) -> Option<bool> {
+        match self {
+            Self::Won => Some(game.player.won),
+            Self::Played(role) => Some(game.player.role == *role),
+            Self::Banished => Some(game.player.banished),
+            Self::CorrectReads(reads) => Some(game.player.correct_reads >= *reads),
+            Self::ExperimentedOn(times) => Some(game.player.times_experimented_on >= *times),
+            Self::VotedForAtMost(votes) => Some(game.votes_against()? <= *votes),
+            Self::VotedForBanished => game.voted_for_banished(),
+            // one that's known to be false (or true, for `Any`) settles it,
+            // even if others can't be known
+            Self::All(conditions) => {
+                let checked = conditions.iter().map(|condition| condition.check(game));
+                if checked.clone().any(|holds| holds == Some(false)) {
+                    Some(false)
+                } else {
+                    checked.collect::<Option<Vec<_>>>().map(|_| true)
+                }
+            }
+            Self::Any(conditions) => {
+                let checked = conditions.iter().map(|condition| condition.check(game));
+                if checked.clone().any(|holds| holds == Some(true)) {
+                    Some(true)
+                } else {
+                    checked.collect::<Option<Vec<_>>>().map(|_| false)
+                }
+            }
+            Self::Not(condition) => condition.check(game).map(|holds| !holds),
+        }
+    }
+}
+
+/// A user's achievements, keyed by user id.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Unlocked {
+    /// Achievement id to when it was first unlocked, in unix seconds.
+    pub achievements: BTreeMap<String, u64>,
+}
+
+impl Versioned for Unlocked {
+    const KIND: &'static str = "achievements";
+    const MIGRATIONS: &'static [Upgrade] = &[];
+}
+
+pub fn get(db: &Database, user_id: &str) -> Result<Unlocked, MagicError> {
+    Ok(db.get(TREE, user_id)?.unwrap_or_default())
+}
+
+/// Unlocks whatever everyone earned in a finished game.
+pub fn record(
+    db: &Database,
+    result: &GameResult,
+    events: Option<&[LoggedEvent]>,
+) -> Result<(), MagicError> {
+    let now = crate::now();
+
+    for player in &result.players {
+        let game = PlayerGame {
+            player,
+            result,
+            events,
+        };
+        let earned = ALL
+            .iter()
+            .filter(|achievement| achievement.condition.holds(&game))
+            .collect::<Vec<_>>();
+
+        if earned.is_empty() {
+            continue;
+        }
+
+        db.update(TREE, &player.user_id, |unlocked: Option<Unlocked>| {
+            let mut unlocked = unlocked.unwrap_or_default();
+
+            for achievement in &earned {
+                unlocked
+                    .achievements
+                    .entry(achievement.id.to_string())
+                    .or_insert(now);
+            }
+
+            Some(unlocked)
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::Role;
+
+    fn earned(
+        player: &PlayerResult,
+        result: &GameResult,
+        events: Option<&[LoggedEvent]>,
+    ) -> Vec<&'static str> {
+        let game = PlayerGame {
+            player,
+            result,
+            events,
+        };
+        ALL.iter()
+            .filter(|achievement| achievement.condition.holds(&game))
+            .map(|achievement| achievement.id)
+            .collect()
+    }
+
+    #[test]
+    fn nothing_about_events_holds_without_them() {
+        let magician = PlayerResult {
+            user_id: "magician".to_string(),
+            role: Role::Magician,
+            won: true,
+            banished: false,
+            times_experimented_on: 0,
+            correct_reads: 0,
+        };
+        let result = GameResult {
+            game_id: "7".to_string(),
+            guild_id: "guild".to_string(),
+            channel_id: "channel".to_string(),
+            players: vec![magician.clone()],
+        };
+
+        // nobody voted for them, as far as the log says
+        assert_eq!(
+            earned(&magician, &result, Some(&[])),
+            vec!["first-win", "untouchable-magician"]
+        );
+        // but without a log, we can't know that
+        assert_eq!(earned(&magician, &result, None), vec!["first-win"]);
+
+        let unknown = Condition::Not(&Condition::VotedForBanished);
+        let game = PlayerGame {
+            player: &magician,
+            result: &result,
+            events: None,
+        };
+        assert!(!unknown.holds(&game));
+        assert!(Condition::Any(&[Condition::Won, Condition::VotedForBanished]).holds(&game));
+        assert!(!Condition::All(&[Condition::Won, Condition::VotedForBanished]).holds(&game));
+    }
+}
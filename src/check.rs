@@ -0,0 +1,297 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::history::{self, GameSummary};
+use crate::{state, Database, MagicError};
+
+/// Something wrong between the `lobbies` and `players` trees, or between them
+/// and the game history.
+#[derive(Debug)]
+pub enum Problem {
+    /// The player is mapped to a lobby that doesn't exist.
+    MissingLobby { player: String, lobby: String },
+    /// The player is mapped to a lobby that doesn't list them.
+    UnlistedPlayer { player: String, lobby: String },
+    /// The lobby lists a player who isn't mapped to it.
+    StrayMember { lobby: String, player: String },
+    /// The lobby's creator isn't in it anymore, so nobody can disband it.
+    MissingCreator { lobby: String, creator: String },
+    /// The lobby isn't what its game's events add up to.
+    Diverged { lobby: String, game: String },
+    /// The history says the game is still going, but its lobby is gone.
+    UnfinishedGame { game: String, lobby: String },
+    Undecodable {
+        tree: &'static str,
+        key: String,
+        error: String,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MissingLobby { player, lobby } => {
+                write!(
+                    f,
+                    "player {} is in lobby {}, which doesn't exist",
+                    player, lobby
+                )
+            }
+            Self::UnlistedPlayer { player, lobby } => write!(
+                f,
+                "player {} is in lobby {}, which doesn't list them",
+                player, lobby
+            ),
+            Self::StrayMember { lobby, player } => write!(
+                f,
+                "lobby {} lists player {}, who isn't in it",
+                lobby, player
+            ),
+            Self::MissingCreator { lobby, creator } => write!(
+                f,
+                "lobby {} was made by {}, who isn't in it",
+                lobby, creator
+            ),
+            Self::Diverged { lobby, game } => write!(
+                f,
+                "lobby {} doesn't match the events of game {}",
+                lobby, game
+            ),
+            Self::UnfinishedGame { game, lobby } => write!(
+                f,
+                "game {} never ended, but lobby {} isn't playing it",
+                game, lobby
+            ),
+            Self::Undecodable { tree, key, error } => {
+                write!(f, "{} {} can't be read: {}", tree, key, error)
+            }
+        }
+    }
+}
+
+/// Looks for disagreements between lobbies and players. Nothing is changed.
+pub fn check(db: &Database) -> Result<Vec<Problem>, MagicError> {
+    let mut problems = vec![];
+    let mut lobbies = HashMap::new();
+    let mut players = HashMap::new();
+    let mut unreadable_lobbies = HashSet::new();
+
+    for (lobby_id, lobby) in db.scan_lobbies()? {
+        match lobby {
+            Ok(lobby) => {
+                lobbies.insert(lobby_id, lobby);
+            }
+            Err(e) => {
+                unreadable_lobbies.insert(lobby_id.clone());
+                problems.push(Problem::Undecodable {
+                    tree: "lobby",
+                    key: lobby_id,
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    for (player_id, lobby_id) in db.scan_players()? {
+        match lobby_id {
+            Ok(lobby_id) => {
+                players.insert(player_id, lobby_id);
+            }
+            Err(e) => problems.push(Problem::Undecodable {
+                tree: "player",
+                key: player_id,
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    for (player_id, lobby_id) in &players {
+        match lobbies.get(lobby_id) {
+            // if we couldn't read the lobby, that's already been reported
+            None if unreadable_lobbies.contains(lobby_id) => {}
+            None => problems.push(Problem::MissingLobby {
+                player: player_id.clone(),
+                lobby: lobby_id.clone(),
+            }),
+            Some(lobby) if !lobby.players.contains(player_id) => {
+                problems.push(Problem::UnlistedPlayer {
+                    player: player_id.clone(),
+                    lobby: lobby_id.clone(),
+                })
+            }
+            Some(_) => {}
+        }
+    }
+
+    for (lobby_id, lobby) in &lobbies {
+        for player_id in &lobby.players {
+            if players.get(player_id) != Some(lobby_id) {
+                problems.push(Problem::StrayMember {
+                    lobby: lobby_id.clone(),
+                    player: player_id.clone(),
+                });
+            }
+        }
+
+        if players.get(&lobby.creator) != Some(lobby_id) {
+            problems.push(Problem::MissingCreator {
+                lobby: lobby_id.clone(),
+                creator: lobby.creator.clone(),
+            });
+        }
+    }
+
+    for (lobby_id, lobby) in &lobbies {
+        // lobbies from before there was history have nothing to check against
+        if lobby.game_id.is_empty() || history::summary(db, &lobby.game_id)?.is_none() {
+            continue;
+        }
+
+        if state::replay(db, &lobby.game_id)?.as_ref() != Some(lobby) {
+            problems.push(Problem::Diverged {
+                lobby: lobby_id.clone(),
+                game: lobby.game_id.clone(),
+            });
+        }
+    }
+
+    for (game_id, summary) in db.scan::<GameSummary>(history::GAMES_TREE)? {
+        match summary {
+            Ok(summary) if summary.ended_at.is_none() => {
+                let playing = match lobbies.get(&summary.channel_id) {
+                    Some(lobby) => lobby.game_id == game_id,
+                    // it might be, we just can't tell
+                    None => unreadable_lobbies.contains(&summary.channel_id),
+                };
+
+                if !playing {
+                    problems.push(Problem::UnfinishedGame {
+                        game: game_id,
+                        lobby: summary.channel_id,
+                    });
+                }
+            }
+            Ok(_) => {}
+            Err(e) => problems.push(Problem::Undecodable {
+                tree: "game",
+                key: game_id,
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    Ok(problems)
+}
+
+impl Problem {
+    /// Fixes this if that can be done without guessing. Returns whether it
+    /// did anything.
+    ///
+    /// The `players` tree is trusted over a lobby's player list, since that's
+    /// what every handler checks first, and the lobby is brought in line by
+    /// emitting the join or leave it missed, so it still adds up to its game's
+    /// events afterwards. Lobbies that don't match their game's events are
+    /// rebuilt from them, and games whose lobby went away are marked
+    /// abandoned. Lobbies without their creator and records we can't read
+    /// (maybe a newer version wrote them) are left for a human.
+    pub fn repair(&self, db: &Database) -> Result<bool, MagicError> {
+        match self {
+            Self::MissingLobby { player, lobby } => db.transaction(|tx| {
+                if tx.player_lobby(player)?.as_ref() == Some(lobby) && tx.lobby(lobby)?.is_none() {
+                    tx.remove_player(player)?;
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            }),
+            Self::UnlistedPlayer { player, lobby } => db.transaction(|tx| {
+                if tx.player_lobby(player)?.as_ref() != Some(lobby) {
+                    return Ok(false);
+                }
+
+                match tx.lobby(lobby)? {
+                    Some(found) if !found.players.contains(player) => {
+                        let joined = history::Event::Joined {
+                            user_id: player.clone(),
+                        };
+                        state::emit(tx, lobby, &found.game_id, joined)?;
+                        Ok(true)
+                    }
+                    _ => Ok(false),
+                }
+            }),
+            Self::StrayMember { lobby, player } => db.transaction(|tx| {
+                if tx.player_lobby(player)?.as_ref() == Some(lobby) {
+                    return Ok(false);
+                }
+
+                match tx.lobby(lobby)? {
+                    Some(found) if found.players.contains(player) => {
+                        let left = history::Event::Left {
+                            user_id: player.clone(),
+                        };
+                        state::emit(tx, lobby, &found.game_id, left)?;
+                        Ok(true)
+                    }
+                    _ => Ok(false),
+                }
+            }),
+            Self::Diverged { lobby, game } => state::rebuild(db, lobby, game),
+            Self::UnfinishedGame { game, lobby } => {
+                // a lobby might have started playing it since we looked
+                let found = db.transaction(|tx| tx.lobby(lobby))?;
+                if found.map(|found| found.game_id).as_ref() == Some(game) {
+                    return Ok(false);
+                }
+
+                history::record(db, game, history::Event::Abandoned)?;
+                Ok(true)
+            }
+            Self::MissingCreator { .. } | Self::Undecodable { .. } => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::Event;
+
+    #[test]
+    fn repairing_members_keeps_lobbies_in_step_with_their_games() {
+        let db = Database::make(sled::Config::new().temporary(true).open().unwrap());
+        let emit = |event: Event| {
+            db.transaction(|tx| state::emit(tx, "channel", "7", event.clone()))
+                .unwrap();
+        };
+
+        emit(Event::Created {
+            guild_id: "guild".to_string(),
+            channel_id: "channel".to_string(),
+            creator: "creator".to_string(),
+        });
+        emit(Event::Joined {
+            user_id: "stray".to_string(),
+        });
+        db.transaction(|tx| {
+            tx.remove_player("stray")?;
+            tx.set_player_lobby("unlisted", "channel")
+        })
+        .unwrap();
+
+        let problems = check(&db).unwrap();
+        assert_eq!(problems.len(), 2);
+        for problem in &problems {
+            assert!(problem.repair(&db).unwrap());
+        }
+
+        assert!(check(&db).unwrap().is_empty());
+        // and doing it again changes nothing
+        for problem in &problems {
+            assert!(!problem.repair(&db).unwrap());
+        }
+
+        let lobby = state::replay(&db, "7").unwrap().unwrap();
+        assert_eq!(lobby.players, vec!["creator", "unlisted"]);
+    }
+}
@@ -0,0 +1,943 @@
+//! Settings for one deployment. They come from, in increasing priority: a
+//! TOML file, `MAGIC_*` environment variables, and flags before the command,
+//! like `magic --bind 127.0.0.1:8000 db check`.
+//!
+//! One deployment can answer for several discord applications (say, a beta
+//! bot next to the main one). The top level settings are the main app's, and
+//! the others get an `[apps.<name>]` table in the file.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::verify::Verifier;
+
+/// Used if `--config` and `MAGIC_CONFIG` aren't given. It's fine if this one
+/// doesn't exist.
+pub const DEFAULT_FILE: &str = "magic.toml";
+/// What the app from the top level settings is called.
+pub const MAIN_APP: &str = "main";
+
+#[cfg(not(feature = "sqlite"))]
+const DEFAULT_DATABASE: &str = "sled.data";
+#[cfg(feature = "sqlite")]
+const DEFAULT_DATABASE: &str = "magic.sqlite";
+// other apps' databases are named after them
+#[cfg(not(feature = "sqlite"))]
+const DATABASE_EXTENSION: &str = "data";
+#[cfg(feature = "sqlite")]
+const DATABASE_EXTENSION: &str = "sqlite";
+
+const DEFAULT_BIND: &str = "0.0.0.0:8000";
+const DEFAULT_SNAPSHOT_DIR: &str = "snapshots";
+const DEFAULT_SNAPSHOT_EVERY: u64 = 60 * 60;
+// a day's worth, at the default
+const DEFAULT_SNAPSHOTS_KEPT: u64 = 24;
+// discord's own retries come within a few seconds, so this is plenty
+const DEFAULT_TIMESTAMP_WINDOW: u64 = 5 * 60;
+// interactions are a few kilobytes at most
+const DEFAULT_MAX_BODY_SIZE: u64 = 64 * 1024;
+const DEFAULT_HEADER_TIMEOUT: u64 = 10;
+// discord itself gives up on us after 3 seconds, so this is really for slow
+// clients trickling a body in, which shouldn't get to hold a connection forever
+const DEFAULT_REQUEST_TIMEOUT: u64 = 30;
+const DEFAULT_MAX_CONCURRENT_REQUESTS: u64 = 256;
+// docker gives 10 seconds before killing us, and there's a flush and a
+// snapshot to fit in after
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 5;
+// a game's worth of joining, voting and leaving doesn't come close
+const DEFAULT_USER_RATE_LIMIT: u64 = 20;
+const DEFAULT_GUILD_RATE_LIMIT: u64 = 120;
+const MIN_ADMIN_TOKEN_LENGTH: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind: SocketAddr,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    /// How far from now an interaction's signed timestamp can be, in
+    /// seconds, before it's turned away.
+    pub timestamp_window: u64,
+    /// Bodies bigger than this many bytes are turned away.
+    pub max_body_size: u64,
+    /// Seconds a client gets to send a request's headers.
+    pub header_timeout: u64,
+    /// Seconds a request gets from its headers arriving to being answered.
+    pub request_timeout: u64,
+    /// Requests being worked on at once. Any more are turned away until some
+    /// finish.
+    pub max_concurrent_requests: u64,
+    /// Seconds that requests being worked on, and a sweep or snapshot that's
+    /// underway, get to finish between them when shutting down.
+    pub shutdown_timeout: u64,
+    /// Interactions a minute one user can send, in bursts of up to that
+    /// many. Past it they're told to slow down.
+    pub user_rate_limit: u32,
+    /// The same, for everyone in a guild together.
+    pub guild_rate_limit: u32,
+    /// Seconds between snapshots of each app's database.
+    pub snapshot_every: u64,
+    /// How many snapshots each app keeps. Older ones are deleted.
+    pub snapshots_kept: usize,
+    /// Where the certificate and key are, to serve https with `--features
+    /// tls`. Plain http otherwise.
+    pub tls: Option<Tls>,
+    /// What `/admin` requests have to bring. They're all turned away without
+    /// one.
+    pub admin_token: Option<String>,
+    /// The main app first, then the others by name.
+    pub apps: Vec<App>,
+}
+
+/// PEM files, read again when they change.
+#[derive(Debug, Clone)]
+pub struct Tls {
+    /// The certificate, followed by any intermediate ones.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// One discord application we answer for. Each keeps its data to itself.
+#[derive(Debug, Clone)]
+pub struct App {
+    pub name: String,
+    /// Checks against discord's keys for the application. There's more than
+    /// one while rotating, and only serving needs any.
+    pub verifier: Verifier,
+    /// sled's directory, or sqlite's file with `--features sqlite`.
+    pub database: PathBuf,
+    pub snapshot_dir: PathBuf,
+    /// For posting to channels outside of interactions.
+    pub bot_token: Option<String>,
+    pub commands: Commands,
+}
+
+impl Config {
+    pub fn app(&self, name: &str) -> Option<&App> {
+        self.apps.iter().find(|app| app.name == name)
+    }
+
+    pub fn main_app(&self) -> &App {
+        &self.apps[0]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+/// How log lines look. JSON is for feeding them to something else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s.to_ascii_lowercase().as_str() {
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The ids discord gave our registered commands. They differ between
+/// applications, so staging and production need their own.
+#[derive(Debug, Clone)]
+pub struct Commands {
+    pub create_lobby: String,
+    pub join_lobby: String,
+    pub kill_player: String,
+    pub vote_player: String,
+    pub leave_lobby: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    CreateLobby,
+    JoinLobby,
+    KillPlayer,
+    VotePlayer,
+    LeaveLobby,
+}
+
+impl Commands {
+    /// Which command has this id, if it's one of ours.
+    pub fn command(&self, id: &str) -> Option<Command> {
+        [
+            (&self.create_lobby, Command::CreateLobby),
+            (&self.join_lobby, Command::JoinLobby),
+            (&self.kill_player, Command::KillPlayer),
+            (&self.vote_player, Command::VotePlayer),
+            (&self.leave_lobby, Command::LeaveLobby),
+        ]
+        .iter()
+        .find(|(command_id, _)| command_id.as_str() == id)
+        .map(|(_, command)| *command)
+    }
+}
+
+impl Default for Commands {
+    // the ones registered for the original application
+    fn default() -> Self {
+        Self {
+            create_lobby: "796995810038382642".to_string(),
+            join_lobby: "796996870815744010".to_string(),
+            kill_player: "796999207046742027".to_string(),
+            vote_player: "796999927782834176".to_string(),
+            leave_lobby: "801198519263559690".to_string(),
+        }
+    }
+}
+
+/// Settings as given, before they're checked. Every source fills one of
+/// these, and later ones win.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Raw {
+    #[serde(deserialize_with = "keys")]
+    pub public_key: Option<String>,
+    pub bind: Option<String>,
+    pub database: Option<String>,
+    pub snapshot_dir: Option<String>,
+    pub bot_token: Option<String>,
+    pub admin_token: Option<String>,
+    pub log_level: Option<String>,
+    pub log_format: Option<String>,
+    #[serde(deserialize_with = "text")]
+    pub timestamp_window: Option<String>,
+    #[serde(deserialize_with = "text")]
+    pub max_body_size: Option<String>,
+    #[serde(deserialize_with = "text")]
+    pub header_timeout: Option<String>,
+    #[serde(deserialize_with = "text")]
+    pub request_timeout: Option<String>,
+    #[serde(deserialize_with = "text")]
+    pub max_concurrent_requests: Option<String>,
+    #[serde(deserialize_with = "text")]
+    pub shutdown_timeout: Option<String>,
+    #[serde(deserialize_with = "text")]
+    pub user_rate_limit: Option<String>,
+    #[serde(deserialize_with = "text")]
+    pub guild_rate_limit: Option<String>,
+    #[serde(deserialize_with = "text")]
+    pub snapshot_every: Option<String>,
+    #[serde(deserialize_with = "text")]
+    pub snapshots_kept: Option<String>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub commands: RawCommands,
+    /// Only the file can have these.
+    pub apps: BTreeMap<String, RawApp>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RawApp {
+    #[serde(deserialize_with = "keys")]
+    pub public_key: Option<String>,
+    pub database: Option<String>,
+    pub snapshot_dir: Option<String>,
+    pub bot_token: Option<String>,
+    pub commands: RawCommands,
+}
+
+/// Keys can be a list in the file. Everywhere else they're separated by
+/// commas, so lists are joined up the same way.
+fn keys<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Keys {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(
+        Option::<Keys>::deserialize(deserializer)?.map(|keys| match keys {
+            Keys::One(key) => key,
+            Keys::Many(keys) => keys.join(","),
+        }),
+    )
+}
+
+/// Numbers can be numbers in the file, but they're text everywhere else, so
+/// they're kept as text until they're checked.
+fn text<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Text {
+        Text(String),
+        Number(i64),
+    }
+
+    Ok(
+        Option::<Text>::deserialize(deserializer)?.map(|text| match text {
+            Text::Text(text) => text,
+            Text::Number(number) => number.to_string(),
+        }),
+    )
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RawCommands {
+    pub create_lobby: Option<String>,
+    pub join_lobby: Option<String>,
+    pub kill_player: Option<String>,
+    pub vote_player: Option<String>,
+    pub leave_lobby: Option<String>,
+}
+
+impl Raw {
+    /// Overrides whatever's set in `other`.
+    fn merge(&mut self, other: Self) {
+        fn take(into: &mut Option<String>, from: Option<String>) {
+            if from.is_some() {
+                *into = from;
+            }
+        }
+
+        take(&mut self.public_key, other.public_key);
+        take(&mut self.bind, other.bind);
+        take(&mut self.database, other.database);
+        take(&mut self.snapshot_dir, other.snapshot_dir);
+        take(&mut self.bot_token, other.bot_token);
+        take(&mut self.admin_token, other.admin_token);
+        take(&mut self.log_level, other.log_level);
+        take(&mut self.log_format, other.log_format);
+        take(&mut self.timestamp_window, other.timestamp_window);
+        take(&mut self.max_body_size, other.max_body_size);
+        take(&mut self.header_timeout, other.header_timeout);
+        take(&mut self.request_timeout, other.request_timeout);
+        take(
+            &mut self.max_concurrent_requests,
+            other.max_concurrent_requests,
+        );
+        take(&mut self.shutdown_timeout, other.shutdown_timeout);
+        take(&mut self.user_rate_limit, other.user_rate_limit);
+        take(&mut self.guild_rate_limit, other.guild_rate_limit);
+        take(&mut self.snapshot_every, other.snapshot_every);
+        take(&mut self.snapshots_kept, other.snapshots_kept);
+        take(&mut self.tls_cert, other.tls_cert);
+        take(&mut self.tls_key, other.tls_key);
+        take(&mut self.commands.create_lobby, other.commands.create_lobby);
+        take(&mut self.commands.join_lobby, other.commands.join_lobby);
+        take(&mut self.commands.kill_player, other.commands.kill_player);
+        take(&mut self.commands.vote_player, other.commands.vote_player);
+        take(&mut self.commands.leave_lobby, other.commands.leave_lobby);
+        self.apps.extend(other.apps);
+    }
+
+    fn from_env(var: impl Fn(&str) -> Option<String>) -> Self {
+        Self {
+            // before there was a config, this was baked in at build time
+            // from `.env`, so deployments still have it under this name
+            public_key: var("MAGIC_PUBLIC_KEY").or_else(|| var("PUBLIC_KEY")),
+            bind: var("MAGIC_BIND"),
+            database: var("MAGIC_DATABASE"),
+            snapshot_dir: var("MAGIC_SNAPSHOT_DIR"),
+            bot_token: var("MAGIC_BOT_TOKEN"),
+            admin_token: var("MAGIC_ADMIN_TOKEN"),
+            log_level: var("MAGIC_LOG_LEVEL"),
+            log_format: var("MAGIC_LOG_FORMAT"),
+            timestamp_window: var("MAGIC_TIMESTAMP_WINDOW"),
+            max_body_size: var("MAGIC_MAX_BODY_SIZE"),
+            header_timeout: var("MAGIC_HEADER_TIMEOUT"),
+            request_timeout: var("MAGIC_REQUEST_TIMEOUT"),
+            max_concurrent_requests: var("MAGIC_MAX_CONCURRENT_REQUESTS"),
+            shutdown_timeout: var("MAGIC_SHUTDOWN_TIMEOUT"),
+            user_rate_limit: var("MAGIC_USER_RATE_LIMIT"),
+            guild_rate_limit: var("MAGIC_GUILD_RATE_LIMIT"),
+            snapshot_every: var("MAGIC_SNAPSHOT_EVERY"),
+            snapshots_kept: var("MAGIC_SNAPSHOTS_KEPT"),
+            tls_cert: var("MAGIC_TLS_CERT"),
+            tls_key: var("MAGIC_TLS_KEY"),
+            commands: RawCommands {
+                create_lobby: var("MAGIC_COMMANDS_CREATE_LOBBY"),
+                join_lobby: var("MAGIC_COMMANDS_JOIN_LOBBY"),
+                kill_player: var("MAGIC_COMMANDS_KILL_PLAYER"),
+                vote_player: var("MAGIC_COMMANDS_VOTE_PLAYER"),
+                leave_lobby: var("MAGIC_COMMANDS_LEAVE_LOBBY"),
+            },
+            apps: BTreeMap::new(),
+        }
+    }
+}
+
+/// Everything wrong with the settings, so they can all be fixed in one go.
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "bad configuration:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// What's given before the command.
+#[derive(Debug, Default)]
+pub struct Flags {
+    /// `--config`
+    pub file: Option<String>,
+    /// `--app`, which app's data the `db` commands work on.
+    pub app: Option<String>,
+    pub settings: Raw,
+}
+
+/// Pulls the flags off the front of `args`, returning them and what's left.
+pub fn parse_flags(args: &[String]) -> Result<(Flags, Vec<String>), ConfigError> {
+    let mut flags = Flags::default();
+    let raw = &mut flags.settings;
+    let mut rest = args.iter();
+    let mut problems = vec![];
+
+    let remaining = loop {
+        let flag = match rest.as_slice().first() {
+            Some(flag) if flag.starts_with("--") => flag.clone(),
+            _ => break rest.cloned().collect(),
+        };
+        rest.next();
+
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (flag.clone(), rest.next().cloned()),
+        };
+        let value = match value {
+            Some(value) => value,
+            None => {
+                problems.push(format!("{} needs a value", name));
+                continue;
+            }
+        };
+
+        match name.as_str() {
+            "--config" => flags.file = Some(value),
+            "--app" => flags.app = Some(value),
+            "--public-key" => raw.public_key = Some(value),
+            "--bind" => raw.bind = Some(value),
+            "--database" => raw.database = Some(value),
+            "--snapshot-dir" => raw.snapshot_dir = Some(value),
+            "--log-level" => raw.log_level = Some(value),
+            "--log-format" => raw.log_format = Some(value),
+            "--timestamp-window" => raw.timestamp_window = Some(value),
+            "--max-body-size" => raw.max_body_size = Some(value),
+            "--header-timeout" => raw.header_timeout = Some(value),
+            "--request-timeout" => raw.request_timeout = Some(value),
+            "--max-concurrent-requests" => raw.max_concurrent_requests = Some(value),
+            "--shutdown-timeout" => raw.shutdown_timeout = Some(value),
+            "--user-rate-limit" => raw.user_rate_limit = Some(value),
+            "--guild-rate-limit" => raw.guild_rate_limit = Some(value),
+            "--snapshot-every" => raw.snapshot_every = Some(value),
+            "--snapshots-kept" => raw.snapshots_kept = Some(value),
+            "--tls-cert" => raw.tls_cert = Some(value),
+            "--tls-key" => raw.tls_key = Some(value),
+            "--admin-token" => raw.admin_token = Some(value),
+            "--commands-create-lobby" => raw.commands.create_lobby = Some(value),
+            "--commands-join-lobby" => raw.commands.join_lobby = Some(value),
+            "--commands-kill-player" => raw.commands.kill_player = Some(value),
+            "--commands-vote-player" => raw.commands.vote_player = Some(value),
+            "--commands-leave-lobby" => raw.commands.leave_lobby = Some(value),
+            _ => problems.push(format!("unknown flag {}", name)),
+        }
+    };
+
+    if problems.is_empty() {
+        Ok((flags, remaining))
+    } else {
+        Err(ConfigError(problems))
+    }
+}
+
+impl Config {
+    /// Reads the file, then the environment, then `flags` on top.
+    pub fn load(file: Option<String>, flags: Raw) -> Result<Self, ConfigError> {
+        let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+
+        // a missing file is only a problem if someone asked for it
+        let (path, required) = match file.or_else(|| env("MAGIC_CONFIG")) {
+            Some(path) => (path, true),
+            None => (DEFAULT_FILE.to_string(), false),
+        };
+        let mut raw = match std::fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|e| ConfigError(vec![format!("{} isn't valid: {}", path, e)]))?,
+            Err(e) if required || e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(ConfigError(vec![format!("can't read {}: {}", path, e)]))
+            }
+            Err(_) => Raw::default(),
+        };
+
+        raw.merge(Raw::from_env(env));
+        raw.merge(flags);
+
+        Self::validate(raw)
+    }
+
+    pub fn validate(raw: Raw) -> Result<Self, ConfigError> {
+        let mut problems = vec![];
+
+        let bind = raw.bind.unwrap_or_else(|| DEFAULT_BIND.to_string());
+        let bind = bind.parse().unwrap_or_else(|e| {
+            problems.push(format!(
+                "bind should be an address and port like {}, not {:?}: {}",
+                DEFAULT_BIND, bind, e
+            ));
+            ([0, 0, 0, 0], 8000).into()
+        });
+
+        let log_level = raw.log_level.map_or(LogLevel::Info, |level| {
+            level.parse().unwrap_or_else(|()| {
+                problems.push(format!(
+                    "log_level should be error, warn, info or debug, not {:?}",
+                    level
+                ));
+                LogLevel::Info
+            })
+        });
+        let log_format = raw.log_format.map_or(LogFormat::Pretty, |format| {
+            format.parse().unwrap_or_else(|()| {
+                problems.push(format!(
+                    "log_format should be pretty or json, not {:?}",
+                    format
+                ));
+                LogFormat::Pretty
+            })
+        });
+
+        let timestamp_window = number(
+            &mut problems,
+            "timestamp_window",
+            raw.timestamp_window,
+            DEFAULT_TIMESTAMP_WINDOW,
+        );
+        let max_body_size = number(
+            &mut problems,
+            "max_body_size",
+            raw.max_body_size,
+            DEFAULT_MAX_BODY_SIZE,
+        );
+        let header_timeout = number(
+            &mut problems,
+            "header_timeout",
+            raw.header_timeout,
+            DEFAULT_HEADER_TIMEOUT,
+        );
+        let request_timeout = number(
+            &mut problems,
+            "request_timeout",
+            raw.request_timeout,
+            DEFAULT_REQUEST_TIMEOUT,
+        );
+        let max_concurrent_requests = number(
+            &mut problems,
+            "max_concurrent_requests",
+            raw.max_concurrent_requests,
+            DEFAULT_MAX_CONCURRENT_REQUESTS,
+        );
+        let shutdown_timeout = number(
+            &mut problems,
+            "shutdown_timeout",
+            raw.shutdown_timeout,
+            DEFAULT_SHUTDOWN_TIMEOUT,
+        );
+        let user_rate_limit = number(
+            &mut problems,
+            "user_rate_limit",
+            raw.user_rate_limit,
+            DEFAULT_USER_RATE_LIMIT,
+        );
+        let guild_rate_limit = number(
+            &mut problems,
+            "guild_rate_limit",
+            raw.guild_rate_limit,
+            DEFAULT_GUILD_RATE_LIMIT,
+        );
+        let snapshot_every = number(
+            &mut problems,
+            "snapshot_every",
+            raw.snapshot_every,
+            DEFAULT_SNAPSHOT_EVERY,
+        );
+        let snapshots_kept = number(
+            &mut problems,
+            "snapshots_kept",
+            raw.snapshots_kept,
+            DEFAULT_SNAPSHOTS_KEPT,
+        );
+
+        let tls = match (raw.tls_cert, raw.tls_key) {
+            (Some(cert), Some(key)) if !cert.is_empty() && !key.is_empty() => {
+                if !cfg!(feature = "tls") {
+                    problems.push(
+                        "tls_cert and tls_key need magic built with --features tls".to_string(),
+                    );
+                }
+                Some(Tls {
+                    cert: cert.into(),
+                    key: key.into(),
+                })
+            }
+            (None, None) => None,
+            _ => {
+                problems.push("tls_cert and tls_key have to be set together".to_string());
+                None
+            }
+        };
+
+        // anyone who guesses it can read every game, so it can't be a short one
+        let admin_token = raw.admin_token.filter(|token| !token.is_empty());
+        if let Some(token) = &admin_token {
+            if token.len() < MIN_ADMIN_TOKEN_LENGTH {
+                problems.push(format!(
+                    "admin_token should be at least {} characters long",
+                    MIN_ADMIN_TOKEN_LENGTH
+                ));
+            }
+            if !token.bytes().all(|b| b.is_ascii_graphic()) {
+                problems.push(
+                    "admin_token can only have printable characters, without spaces".to_string(),
+                );
+            }
+        }
+
+        let mut apps = vec![App::validate(
+            &mut problems,
+            MAIN_APP,
+            RawApp {
+                public_key: raw.public_key,
+                database: raw.database,
+                snapshot_dir: raw.snapshot_dir,
+                bot_token: raw.bot_token,
+                commands: raw.commands,
+            },
+        )];
+        for (name, app) in raw.apps {
+            if name == MAIN_APP {
+                problems.push(format!(
+                    "apps.{} can't be called that, the top level settings are {0}'s",
+                    name
+                ));
+                continue;
+            }
+            if name.is_empty()
+                || !name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+            {
+                problems.push(format!(
+                    "apps.{:?} should be named with letters, digits, - and _",
+                    name
+                ));
+            }
+            apps.push(App::validate(&mut problems, &name, app));
+        }
+
+        // two apps sharing these would see each other's data
+        for (i, app) in apps.iter().enumerate() {
+            for other in &apps[..i] {
+                if app.database == other.database {
+                    problems.push(format!(
+                        "{} and {} can't both use the database {}",
+                        other.name,
+                        app.name,
+                        app.database.display()
+                    ));
+                }
+                if app.snapshot_dir == other.snapshot_dir {
+                    problems.push(format!(
+                        "{} and {} can't both use the snapshot_dir {}",
+                        other.name,
+                        app.name,
+                        app.snapshot_dir.display()
+                    ));
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(Self {
+                bind,
+                log_level,
+                log_format,
+                timestamp_window,
+                max_body_size,
+                header_timeout,
+                request_timeout,
+                max_concurrent_requests,
+                shutdown_timeout,
+                user_rate_limit: u32::try_from(user_rate_limit).unwrap_or(u32::MAX),
+                guild_rate_limit: u32::try_from(guild_rate_limit).unwrap_or(u32::MAX),
+                snapshot_every,
+                snapshots_kept: usize::try_from(snapshots_kept).unwrap_or(usize::MAX),
+                tls,
+                admin_token,
+                apps,
+            })
+        } else {
+            Err(ConfigError(problems))
+        }
+    }
+}
+
+impl App {
+    fn validate(problems: &mut Vec<String>, name: &str, raw: RawApp) -> Self {
+        let main = name == MAIN_APP;
+        // problems with the main app's settings are about the top level ones
+        let setting = |setting: &str| {
+            if main {
+                setting.to_string()
+            } else {
+                format!("apps.{}.{}", name, setting)
+            }
+        };
+
+        let mut verifier = Verifier::default();
+        for key in raw.public_key.iter().flat_map(|keys| keys.split(',')) {
+            if let Err(e) = verifier.add_key(key) {
+                problems.push(format!("{} {}", setting("public_key"), e));
+            }
+        }
+        // the main app can do without, for the `db` commands
+        if raw.public_key.is_none() && !main {
+            problems.push(format!("{} has to be set", setting("public_key")));
+        }
+
+        let database = raw.database.unwrap_or_else(|| {
+            if main {
+                DEFAULT_DATABASE.to_string()
+            } else {
+                format!("{}.{}", name, DATABASE_EXTENSION)
+            }
+        });
+        if database.is_empty() {
+            problems.push(format!("{} can't be empty", setting("database")));
+        }
+        let snapshot_dir = raw.snapshot_dir.unwrap_or_else(|| {
+            if main {
+                DEFAULT_SNAPSHOT_DIR.to_string()
+            } else {
+                format!("{}-{}", DEFAULT_SNAPSHOT_DIR, name)
+            }
+        });
+        if snapshot_dir.is_empty() {
+            problems.push(format!("{} can't be empty", setting("snapshot_dir")));
+        }
+
+        // other applications registered their own commands, so they don't
+        // get the main app's ids
+        let defaults = if main {
+            Some(Commands::default())
+        } else {
+            None
+        };
+        let mut command = |command: &str, id: Option<String>, default: Option<String>| {
+            let name = setting(&format!("commands.{}", command));
+            match id.or(default) {
+                Some(id) if !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()) => id,
+                Some(id) => {
+                    problems.push(format!(
+                        "{} should be the command's id (all digits), not {:?}",
+                        name, id
+                    ));
+                    id
+                }
+                None => {
+                    problems.push(format!("{} has to be set", name));
+                    String::new()
+                }
+            }
+        };
+        let commands = Commands {
+            create_lobby: command(
+                "create_lobby",
+                raw.commands.create_lobby,
+                defaults.as_ref().map(|d| d.create_lobby.clone()),
+            ),
+            join_lobby: command(
+                "join_lobby",
+                raw.commands.join_lobby,
+                defaults.as_ref().map(|d| d.join_lobby.clone()),
+            ),
+            kill_player: command(
+                "kill_player",
+                raw.commands.kill_player,
+                defaults.as_ref().map(|d| d.kill_player.clone()),
+            ),
+            vote_player: command(
+                "vote_player",
+                raw.commands.vote_player,
+                defaults.as_ref().map(|d| d.vote_player.clone()),
+            ),
+            leave_lobby: command(
+                "leave_lobby",
+                raw.commands.leave_lobby,
+                defaults.map(|d| d.leave_lobby),
+            ),
+        };
+
+        Self {
+            name: name.to_string(),
+            verifier,
+            database: database.into(),
+            snapshot_dir: snapshot_dir.into(),
+            bot_token: raw.bot_token.filter(|token| !token.is_empty()),
+            commands,
+        }
+    }
+}
+
+/// A setting that has to be a whole number above zero.
+fn number(problems: &mut Vec<String>, name: &str, value: Option<String>, default: u64) -> u64 {
+    match value.map(|value| value.trim().parse::<u64>()) {
+        None => default,
+        Some(Ok(number)) if number > 0 => number,
+        _ => {
+            problems.push(format!("{} should be a whole number above zero", name));
+            default
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn command_ids_and_the_admin_token_can_be_flags() {
+        let (flags, rest) = parse_flags(&args(&[
+            "--commands-create-lobby",
+            "123",
+            "--commands-leave-lobby=456",
+            "--admin-token",
+            "a-long-enough-admin-token",
+            "db",
+            "check",
+        ]))
+        .unwrap();
+        assert_eq!(rest, args(&["db", "check"]));
+
+        let config = Config::validate(flags.settings).unwrap();
+        assert_eq!(config.main_app().commands.create_lobby, "123");
+        assert_eq!(config.main_app().commands.leave_lobby, "456");
+        assert_eq!(
+            config.admin_token.as_deref(),
+            Some("a-long-enough-admin-token")
+        );
+    }
+
+    #[test]
+    fn short_admin_tokens_are_refused() {
+        let raw = Raw {
+            admin_token: Some("hunter2".to_string()),
+            ..Raw::default()
+        };
+        assert!(Config::validate(raw).is_err());
+
+        // empty is the same as not set
+        let raw = Raw {
+            admin_token: Some(String::new()),
+            ..Raw::default()
+        };
+        assert!(Config::validate(raw).unwrap().admin_token.is_none());
+    }
+
+    #[test]
+    fn the_old_public_key_variable_still_works() {
+        let key = "ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c";
+        let env = |name: &str| match name {
+            "PUBLIC_KEY" | "BOT_TOKEN" => Some(key.to_string()),
+            _ => None,
+        };
+
+        let raw = Raw::from_env(env);
+        assert_eq!(raw.public_key.as_deref(), Some(key));
+        // the rest were never read from anywhere but MAGIC_*
+        assert!(raw.bot_token.is_none());
+
+        let env = |name: &str| match name {
+            "MAGIC_PUBLIC_KEY" => Some("the new one".to_string()),
+            "PUBLIC_KEY" => Some(key.to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            Raw::from_env(env).public_key.as_deref(),
+            Some("the new one")
+        );
+    }
+
+    const KEY: &str = "ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c";
+    const COMMANDS: &str = "create_lobby = \"1\"\njoin_lobby = \"2\"\nkill_player = \"3\"\nvote_player = \"4\"\nleave_lobby = \"5\"\n";
+
+    /// The main app, then `apps`.
+    fn file(apps: &[String]) -> String {
+        format!(
+            "public_key = {:?}\n[commands]\n{}{}",
+            KEY,
+            COMMANDS,
+            apps.concat()
+        )
+    }
+
+    fn app(name: &str, extra: &str) -> String {
+        format!(
+            "[apps.{}]\npublic_key = {:?}\n{}\n[apps.{0}.commands]\n{}",
+            name, KEY, extra, COMMANDS
+        )
+    }
+
+    fn problems(apps: &[String]) -> String {
+        let raw = toml::from_str(&file(apps)).unwrap();
+        Config::validate(raw).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn apps_need_names_and_places_of_their_own() {
+        let config = Config::validate(toml::from_str(&file(&[app("beta", "")])).unwrap());
+        assert!(config.unwrap().app("beta").is_some());
+
+        // the file can't name the same one twice
+        let twice = file(&[app("beta", ""), app("beta", "")]);
+        assert!(toml::from_str::<Raw>(&twice).is_err());
+        // or use the top level's name
+        assert!(problems(&[app("main", "")]).contains("can't be called that"));
+        let shared = format!("database = {:?}", DEFAULT_DATABASE);
+        assert!(problems(&[app("beta", &shared)]).contains("can't both use the database"));
+    }
+}
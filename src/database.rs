@@ -0,0 +1,661 @@
+use serde::{Deserialize, Serialize};
+use sled::transaction::{
+    ConflictableTransactionError, TransactionError, TransactionalTree, UnabortableTransactionError,
+};
+use sled::Transactional;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::encoding::{self, Versioned};
+use crate::{history, state, MagicError};
+
+/// The trees besides `lobbies` and `players` that transactions can touch.
+/// sled has to know about them up front.
+const TRANSACTIONAL_TREES: [&str; 5] = [
+    history::GAMES_TREE,
+    history::EVENTS_TREE,
+    history::CHANNEL_GAMES_TREE,
+    history::UNFINISHED_TREE,
+    state::SNAPSHOTS_TREE,
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Lobby {
+    pub(crate) creator: String,
+    pub(crate) players: Vec<String>,
+    #[serde(default)]
+    pub(crate) guild_id: String,
+    /// When someone last did something with this lobby, in unix seconds.
+    #[serde(default = "crate::now")]
+    pub(crate) last_active: u64,
+    /// Which game this is in the history. Empty for lobbies made before
+    /// there was one.
+    #[serde(default)]
+    pub(crate) game_id: String,
+}
+
+impl Versioned for Lobby {
+    const KIND: &'static str = "lobby";
+    const MIGRATIONS: &'static [encoding::Upgrade] = &[
+        // v1 only added the version tag
+        |payload| Ok(payload.to_vec()),
+        // v2 added the guild and activity
+        |payload| {
+            #[derive(Deserialize)]
+            struct V1 {
+                creator: String,
+                players: Vec<String>,
+            }
+
+            #[derive(Serialize)]
+            struct V2 {
+                creator: String,
+                players: Vec<String>,
+                guild_id: String,
+                last_active: u64,
+            }
+
+            let old: V1 = bincode::deserialize(payload)
+                .map_err(|e| MagicError::Decoding(format!("bad lobby state: {}", e)))?;
+
+            // we don't know the guild, so it gets the default settings. and
+            // this is as good a time as any to start the idle clock.
+            Ok(bincode::serialize(&V2 {
+                creator: old.creator,
+                players: old.players,
+                guild_id: String::new(),
+                last_active: crate::now(),
+            })
+            .expect("could not serialize lobby?"))
+        },
+        // v3 added the game id
+        |payload| {
+            #[derive(Deserialize)]
+            struct V2 {
+                creator: String,
+                players: Vec<String>,
+                guild_id: String,
+                last_active: u64,
+            }
+
+            #[derive(Serialize)]
+            struct V3 {
+                creator: String,
+                players: Vec<String>,
+                guild_id: String,
+                last_active: u64,
+                game_id: String,
+            }
+
+            let old: V2 = bincode::deserialize(payload)
+                .map_err(|e| MagicError::Decoding(format!("bad lobby state: {}", e)))?;
+
+            // these games started before we kept history, so they don't get any
+            Ok(bincode::serialize(&V3 {
+                creator: old.creator,
+                players: old.players,
+                guild_id: old.guild_id,
+                last_active: old.last_active,
+                game_id: String::new(),
+            })
+            .expect("could not serialize lobby?"))
+        },
+    ];
+}
+
+/// What the `players` tree maps a player to.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Membership {
+    pub(crate) lobby_id: String,
+}
+
+impl Versioned for Membership {
+    const KIND: &'static str = "player";
+    const MIGRATIONS: &'static [encoding::Upgrade] = &[
+        // these used to be the bare channel id
+        |payload| {
+            let lobby_id = std::str::from_utf8(payload)
+                .map_err(|e| MagicError::Decoding(format!("bad player state: {}", e)))?;
+            // a struct with one field is encoded the same as the field
+            Ok(bincode::serialize(lobby_id).expect("could not serialize player?"))
+        },
+    ];
+}
+
+/// Everything a handler can touch while inside a transaction. Both storage
+/// backends implement this, so handlers don't care which one is running.
+pub trait LobbyTransaction {
+    /// Which lobby this player is in, if any.
+    fn player_lobby(&self, player_id: &str) -> Result<Option<String>, TxError>;
+    fn set_player_lobby(&self, player_id: &str, lobby_id: &str) -> Result<(), TxError>;
+    fn remove_player(&self, player_id: &str) -> Result<(), TxError>;
+
+    fn lobby(&self, lobby_id: &str) -> Result<Option<Lobby>, TxError>;
+    fn set_lobby(&self, lobby_id: &str, lobby: &Lobby) -> Result<(), TxError>;
+    fn remove_lobby(&self, lobby_id: &str) -> Result<(), TxError>;
+
+    /// An encoded record from one of the `TRANSACTIONAL_TREES`. Use `get` and
+    /// `put` instead, which take care of the encoding.
+    fn record(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>, TxError>;
+    fn set_record(&self, tree: &str, key: &str, value: Vec<u8>) -> Result<(), TxError>;
+    fn remove_record(&self, tree: &str, key: &str) -> Result<(), TxError>;
+}
+
+impl dyn LobbyTransaction + '_ {
+    pub fn get<T: Versioned>(&self, tree: &str, key: &str) -> Result<Option<T>, TxError> {
+        Ok(self
+            .record(tree, key)?
+            .map(|value| encoding::decode(&value))
+            .transpose()?)
+    }
+
+    pub fn put<T: Versioned>(&self, tree: &str, key: &str, record: &T) -> Result<(), TxError> {
+        self.set_record(tree, key, encoding::encode(record))
+    }
+}
+
+#[derive(Debug)]
+pub enum TxError {
+    // someone else touched the same keys, sled will retry for us
+    Conflict,
+    Abort(MagicError),
+}
+
+impl From<MagicError> for TxError {
+    fn from(err: MagicError) -> Self {
+        Self::Abort(err)
+    }
+}
+
+impl From<UnabortableTransactionError> for TxError {
+    fn from(err: UnabortableTransactionError) -> Self {
+        match err {
+            UnabortableTransactionError::Conflict => Self::Conflict,
+            UnabortableTransactionError::Storage(err) => Self::Abort(err.into()),
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for TxError {
+    fn from(err: rusqlite::Error) -> Self {
+        Self::Abort(err.into())
+    }
+}
+
+/// Every record in a tree by key, including the ones that can't be decoded.
+pub type Scan<T> = Vec<(String, Result<T, MagicError>)>;
+
+#[derive(Clone)]
+pub struct Database {
+    backend: Backend,
+    /// How many times a transaction had to be run again.
+    conflicts: Arc<AtomicU64>,
+}
+
+/// Written and removed again to check the database takes writes.
+const PROBE_TREE: &str = "probe";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Probe {
+    at: u64,
+}
+
+impl Versioned for Probe {
+    const KIND: &'static str = "probe";
+    const MIGRATIONS: &'static [encoding::Upgrade] = &[];
+}
+
+#[derive(Clone)]
+enum Backend {
+    Sled {
+        db: sled::Db,
+        lobbies: sled::Tree,
+        players: sled::Tree,
+        /// The `TRANSACTIONAL_TREES`, in order.
+        records: Vec<sled::Tree>,
+    },
+    #[cfg(feature = "sqlite")]
+    Sqlite(crate::sqlite::Sqlite),
+}
+
+impl Database {
+    pub fn make(db: sled::Db) -> Self {
+        Self {
+            backend: Backend::Sled {
+                db: db.clone(),
+                lobbies: db
+                    .open_tree("lobbies")
+                    .expect("was not able to open lobby tree"),
+                players: db
+                    .open_tree("players")
+                    .expect("was not able to open player tree"),
+                records: TRANSACTIONAL_TREES
+                    .iter()
+                    .map(|tree| db.open_tree(tree).expect("was not able to open a tree"))
+                    .collect(),
+            },
+            conflicts: Arc::default(),
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub fn make_sqlite(db: crate::sqlite::Sqlite) -> Self {
+        Self {
+            backend: Backend::Sqlite(db),
+            conflicts: Arc::default(),
+        }
+    }
+
+    /// Runs `f` atomically against the lobby state. With sled, `f` may be
+    /// run more than once if it conflicts with another request.
+    pub fn transaction<F, R>(&self, f: F) -> Result<R, MagicError>
+    where
+        F: Fn(&dyn LobbyTransaction) -> Result<R, TxError>,
+    {
+        match &self.backend {
+            Backend::Sled {
+                lobbies,
+                players,
+                records,
+                ..
+            } => {
+                let mut trees = vec![lobbies.clone(), players.clone()];
+                trees.extend(records.iter().cloned());
+
+                let first = std::cell::Cell::new(true);
+                trees[..]
+                    .transaction(|trees| {
+                        // sled runs this again when it conflicts
+                        if !first.replace(false) {
+                            self.conflicts.fetch_add(1, Ordering::Relaxed);
+                        }
+
+                        let tx = SledTransaction {
+                            lobbies: &trees[0],
+                            players: &trees[1],
+                            records: &trees[2..],
+                        };
+
+                        f(&tx).map_err(|err| match err {
+                            TxError::Conflict => UnabortableTransactionError::Conflict.into(),
+                            TxError::Abort(err) => ConflictableTransactionError::Abort(err),
+                        })
+                    })
+                    .map_err(|err| match err {
+                        TransactionError::Abort(err) => err,
+                        TransactionError::Storage(err) => err.into(),
+                    })
+            }
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => db.transaction(f),
+        }
+    }
+
+    /// Transactions that had to be run again because of another one. sqlite
+    /// runs them one at a time, so it never has any.
+    pub fn conflicts(&self) -> u64 {
+        self.conflicts.load(Ordering::Relaxed)
+    }
+
+    /// Checks the database is still taking writes, by writing something.
+    pub fn probe(&self) -> Result<(), MagicError> {
+        self.put(PROBE_TREE, "probe", &Probe { at: crate::now() })?;
+        self.remove(PROBE_TREE, "probe")
+    }
+
+    /// Makes sure everything written so far is on disk. sqlite already is by
+    /// the time a transaction commits, so only sled has anything to do.
+    pub fn flush(&self) -> Result<(), MagicError> {
+        match &self.backend {
+            Backend::Sled { db, .. } => {
+                db.flush()?;
+                Ok(())
+            }
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(_) => Ok(()),
+        }
+    }
+
+    /// Whether there's nothing at all stored, in any tree.
+    pub fn is_empty(&self) -> Result<bool, MagicError> {
+        match &self.backend {
+            Backend::Sled { db, .. } => {
+                for name in db.tree_names() {
+                    if !db.open_tree(name)?.is_empty() {
+                        return Ok(false);
+                    }
+                }
+
+                Ok(true)
+            }
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => db.is_empty(),
+        }
+    }
+
+    /// Every lobby, keyed by channel id.
+    pub fn lobbies(&self) -> Result<Vec<(String, Lobby)>, MagicError> {
+        self.scan_lobbies()?
+            .into_iter()
+            .map(|(lobby_id, lobby)| Ok((lobby_id, lobby?)))
+            .collect()
+    }
+
+    /// How many lobbies there are, without reading any of them.
+    pub fn count_lobbies(&self) -> Result<usize, MagicError> {
+        match &self.backend {
+            Backend::Sled { lobbies, .. } => Ok(lobbies.len()),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => db.count_lobbies(),
+        }
+    }
+
+    /// How many records one of the trees that isn't lobby state has, without
+    /// reading them.
+    pub fn count(&self, tree: &str) -> Result<usize, MagicError> {
+        match &self.backend {
+            Backend::Sled { db, .. } => Ok(db.open_tree(tree)?.len()),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => db.count_records(tree),
+        }
+    }
+
+    /// Every player that's in a lobby, with the lobby's channel id.
+    pub fn players(&self) -> Result<Vec<(String, String)>, MagicError> {
+        self.scan_players()?
+            .into_iter()
+            .map(|(player_id, lobby_id)| Ok((player_id, lobby_id?)))
+            .collect()
+    }
+
+    /// Like `lobbies`, but one record that can't be decoded doesn't hide
+    /// the rest.
+    pub fn scan_lobbies(&self) -> Result<Scan<Lobby>, MagicError> {
+        match &self.backend {
+            Backend::Sled { lobbies, .. } => lobbies
+                .iter()
+                .map(|entry| {
+                    let (key, value) = entry?;
+                    Ok((
+                        String::from_utf8_lossy(&key).into_owned(),
+                        encoding::decode(&value),
+                    ))
+                })
+                .collect(),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => Ok(db
+                .lobbies()?
+                .into_iter()
+                .map(|(lobby_id, lobby)| (lobby_id, Ok(lobby)))
+                .collect()),
+        }
+    }
+
+    /// Like `players`, but one record that can't be decoded doesn't hide
+    /// the rest.
+    pub fn scan_players(&self) -> Result<Scan<String>, MagicError> {
+        match &self.backend {
+            Backend::Sled { players, .. } => players
+                .iter()
+                .map(|entry| {
+                    let (key, value) = entry?;
+                    Ok((
+                        String::from_utf8_lossy(&key).into_owned(),
+                        encoding::decode::<Membership>(&value).map(|m| m.lobby_id),
+                    ))
+                })
+                .collect(),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => Ok(db
+                .players()?
+                .into_iter()
+                .map(|(player_id, lobby_id)| (player_id, Ok(lobby_id)))
+                .collect()),
+        }
+    }
+
+    /// Reads from one of the trees that isn't lobby state. Records there are
+    /// only ever read or written one at a time.
+    pub fn get<T: Versioned>(&self, tree: &str, key: &str) -> Result<Option<T>, MagicError> {
+        match &self.backend {
+            Backend::Sled { db, .. } => db
+                .open_tree(tree)?
+                .get(key)?
+                .map(|value| encoding::decode(&value))
+                .transpose(),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => db
+                .get_record(tree, key)?
+                .map(|value| encoding::decode(&value))
+                .transpose(),
+        }
+    }
+
+    pub fn put<T: Versioned>(&self, tree: &str, key: &str, record: &T) -> Result<(), MagicError> {
+        match &self.backend {
+            Backend::Sled { db, .. } => {
+                db.open_tree(tree)?.insert(key, encoding::encode(record))?;
+                Ok(())
+            }
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => db.put_record(tree, key, &encoding::encode(record)),
+        }
+    }
+
+    pub fn remove(&self, tree: &str, key: &str) -> Result<(), MagicError> {
+        match &self.backend {
+            Backend::Sled { db, .. } => {
+                db.open_tree(tree)?.remove(key)?;
+                Ok(())
+            }
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => db.remove_record(tree, key),
+        }
+    }
+
+    /// Atomically replaces a record with whatever `f` makes of it. `f` may be
+    /// called more than once, and returning `None` removes the record.
+    pub fn update<T, F>(&self, tree: &str, key: &str, f: F) -> Result<Option<T>, MagicError>
+    where
+        T: Versioned,
+        F: Fn(Option<T>) -> Option<T>,
+    {
+        match &self.backend {
+            Backend::Sled { db, .. } => {
+                let tree = db.open_tree(tree)?;
+
+                loop {
+                    let old = tree.get(key)?;
+                    let new = f(old
+                        .as_ref()
+                        .map(|value| encoding::decode(value))
+                        .transpose()?);
+                    let new_value = new.as_ref().map(encoding::encode);
+
+                    if tree.compare_and_swap(key, old, new_value)?.is_ok() {
+                        return Ok(new);
+                    }
+                }
+            }
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => db.update_record(tree, key, |old| {
+                let new = f(old.map(encoding::decode::<T>).transpose()?);
+                Ok((new.as_ref().map(encoding::encode), new))
+            }),
+        }
+    }
+
+    /// Every record in one of the trees that isn't lobby state.
+    pub fn scan<T: Versioned>(&self, tree: &str) -> Result<Scan<T>, MagicError> {
+        match &self.backend {
+            Backend::Sled { db, .. } => db
+                .open_tree(tree)?
+                .iter()
+                .map(|entry| {
+                    let (key, value) = entry?;
+                    Ok((
+                        String::from_utf8_lossy(&key).into_owned(),
+                        encoding::decode(&value),
+                    ))
+                })
+                .collect(),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => Ok(db
+                .scan_records(tree)?
+                .into_iter()
+                .map(|(key, value)| (key, encoding::decode(&value)))
+                .collect()),
+        }
+    }
+
+    /// Like `scan`, but only keys starting with `prefix`.
+    pub fn scan_prefix<T: Versioned>(
+        &self,
+        tree: &str,
+        prefix: &str,
+    ) -> Result<Scan<T>, MagicError> {
+        self.scan_prefix_from(tree, prefix, prefix)
+    }
+
+    /// Like `scan_prefix`, but starting at `from`, so the keys before it
+    /// aren't read at all.
+    pub fn scan_prefix_from<T: Versioned>(
+        &self,
+        tree: &str,
+        prefix: &str,
+        from: &str,
+    ) -> Result<Scan<T>, MagicError> {
+        match &self.backend {
+            Backend::Sled { db, .. } => db
+                .open_tree(tree)?
+                .range(from..)
+                .take_while(|entry| match entry {
+                    Ok((key, _)) => key.starts_with(prefix.as_bytes()),
+                    Err(_) => true,
+                })
+                .map(|entry| {
+                    let (key, value) = entry?;
+                    Ok((
+                        String::from_utf8_lossy(&key).into_owned(),
+                        encoding::decode(&value),
+                    ))
+                })
+                .collect(),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => Ok(db
+                .scan_records_prefix(tree, prefix, from)?
+                .into_iter()
+                .map(|(key, value)| (key, encoding::decode(&value)))
+                .collect()),
+        }
+    }
+
+    /// Rewrites every record stored at an old version, so reads don't have to
+    /// upgrade them over and over. Returns how many were rewritten.
+    pub fn migrate_records(&self) -> Result<usize, MagicError> {
+        match &self.backend {
+            Backend::Sled {
+                lobbies, players, ..
+            } => Ok(upgrade_tree::<Lobby>(lobbies)? + upgrade_tree::<Membership>(players)?),
+            // sqlite migrates its schema when it's opened
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(_) => Ok(0),
+        }
+    }
+}
+
+fn upgrade_tree<T: Versioned>(tree: &sled::Tree) -> Result<usize, MagicError> {
+    let mut upgraded = 0;
+
+    for entry in tree.iter() {
+        let (key, value) = entry?;
+
+        if let Some(new_value) = encoding::upgrade::<T>(&value)? {
+            // if this fails, a handler wrote it in the meantime. it's current then.
+            if tree
+                .compare_and_swap(key, Some(value), Some(new_value))?
+                .is_ok()
+            {
+                upgraded += 1;
+            }
+        }
+    }
+
+    Ok(upgraded)
+}
+
+struct SledTransaction<'a> {
+    lobbies: &'a TransactionalTree,
+    players: &'a TransactionalTree,
+    records: &'a [TransactionalTree],
+}
+
+impl SledTransaction<'_> {
+    fn tree(&self, tree: &str) -> &TransactionalTree {
+        let index = TRANSACTIONAL_TREES
+            .iter()
+            .position(|name| *name == tree)
+            .unwrap_or_else(|| panic!("{} isn't usable in transactions", tree));
+
+        &self.records[index]
+    }
+}
+
+impl LobbyTransaction for SledTransaction<'_> {
+    fn player_lobby(&self, player_id: &str) -> Result<Option<String>, TxError> {
+        Ok(self
+            .players
+            .get(player_id)?
+            .map(|membership| encoding::decode::<Membership>(&membership))
+            .transpose()?
+            .map(|membership| membership.lobby_id))
+    }
+
+    fn set_player_lobby(&self, player_id: &str, lobby_id: &str) -> Result<(), TxError> {
+        self.players.insert(
+            player_id,
+            encoding::encode(&Membership {
+                lobby_id: lobby_id.to_string(),
+            }),
+        )?;
+        Ok(())
+    }
+
+    fn remove_player(&self, player_id: &str) -> Result<(), TxError> {
+        self.players.remove(player_id)?;
+        Ok(())
+    }
+
+    fn lobby(&self, lobby_id: &str) -> Result<Option<Lobby>, TxError> {
+        Ok(self
+            .lobbies
+            .get(lobby_id)?
+            .map(|lobby| encoding::decode(&lobby))
+            .transpose()?)
+    }
+
+    fn set_lobby(&self, lobby_id: &str, lobby: &Lobby) -> Result<(), TxError> {
+        self.lobbies.insert(lobby_id, encoding::encode(lobby))?;
+        Ok(())
+    }
+
+    fn remove_lobby(&self, lobby_id: &str) -> Result<(), TxError> {
+        self.lobbies.remove(lobby_id)?;
+        Ok(())
+    }
+
+    fn record(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>, TxError> {
+        Ok(self.tree(tree).get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn set_record(&self, tree: &str, key: &str, value: Vec<u8>) -> Result<(), TxError> {
+        self.tree(tree).insert(key, value)?;
+        Ok(())
+    }
+
+    fn remove_record(&self, tree: &str, key: &str) -> Result<(), TxError> {
+        self.tree(tree).remove(key)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+
+use crate::response_types::Data;
+use crate::MagicError;
+
+const API: &str = "https://discord.com/api/v8";
+// separates the parts of a message with a file. it just can't show up in the
+// file, and our files are json.
+const BOUNDARY: &str = "magic-f5b1c0d2-boundary";
+
+/// Talks to discord's REST API, for when there's no interaction to reply to.
+#[derive(Clone)]
+pub struct Discord {
+    client: Client<HttpsConnector<HttpConnector>>,
+    token: String,
+}
+
+impl Discord {
+    pub fn new(bot_token: String) -> Self {
+        let https = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_only()
+            .enable_http1()
+            .build();
+
+        Self {
+            client: Client::builder().build(https),
+            token: bot_token,
+        }
+    }
+
+    pub async fn send_message(&self, channel_id: &str, data: &Data) -> Result<(), MagicError> {
+        self.post_message(
+            channel_id,
+            "application/json".to_string(),
+            Body::from(serde_json::to_string(data)?),
+        )
+        .await
+    }
+
+    /// Like `send_message`, with a file attached.
+    pub async fn send_file(
+        &self,
+        channel_id: &str,
+        data: &Data,
+        file_name: &str,
+        file: Vec<u8>,
+    ) -> Result<(), MagicError> {
+        let mut body = format!(
+            "--{}\r\ncontent-disposition: form-data; name=\"payload_json\"\r\ncontent-type: application/json\r\n\r\n{}\r\n",
+            BOUNDARY,
+            serde_json::to_string(data)?
+        )
+        .into_bytes();
+        body.extend(
+            format!(
+                "--{}\r\ncontent-disposition: form-data; name=\"file\"; filename=\"{}\"\r\ncontent-type: application/octet-stream\r\n\r\n",
+                BOUNDARY, file_name
+            )
+            .into_bytes(),
+        );
+        body.extend(file);
+        body.extend(format!("\r\n--{}--\r\n", BOUNDARY).into_bytes());
+
+        self.post_message(
+            channel_id,
+            format!("multipart/form-data; boundary={}", BOUNDARY),
+            Body::from(body),
+        )
+        .await
+    }
+
+    async fn post_message(
+        &self,
+        channel_id: &str,
+        content_type: String,
+        body: Body,
+    ) -> Result<(), MagicError> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}/channels/{}/messages", API, channel_id))
+            .header("authorization", format!("Bot {}", self.token))
+            .header("content-type", content_type)
+            .body(body)
+            .expect("could not build a message request?");
+
+        let response = self.client.request(request).await.map_err(|e| {
+            tracing::error!(error = ?e, "hyper error");
+            MagicError::WeirdHTTPError("sending a message".to_string())
+        })?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            tracing::warn!(
+                status = %response.status(),
+                channel = channel_id,
+                "discord turned down a message"
+            );
+            Err(MagicError::WeirdHTTPError("sending a message".to_string()))
+        }
+    }
+}
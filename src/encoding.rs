@@ -0,0 +1,143 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::MagicError;
+
+// every record we store starts with this, followed by a version byte. the old
+// untagged bincode can't start with it: that'd need a 0x4dff byte long creator.
+const MAGIC: [u8; 2] = [0xff, b'M'];
+
+/// Turns a payload of one version into a payload of the next one.
+pub type Upgrade = fn(&[u8]) -> Result<Vec<u8>, MagicError>;
+
+/// Something we keep in the database.
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// What to call this in error messages.
+    const KIND: &'static str;
+
+    /// `MIGRATIONS[n]` upgrades a version `n` payload to version `n + 1`, so
+    /// the current version is however many migrations there are. Version 0 is
+    /// whatever we stored before records were tagged.
+    const MIGRATIONS: &'static [Upgrade];
+
+    fn version() -> u8 {
+        Self::MIGRATIONS.len() as u8
+    }
+}
+
+/// Splits a stored value into its version and payload.
+pub fn version_of(bytes: &[u8]) -> Result<(u8, &[u8]), MagicError> {
+    if bytes.starts_with(&MAGIC) {
+        match bytes.get(MAGIC.len()) {
+            Some(version) => Ok((*version, &bytes[MAGIC.len() + 1..])),
+            None => Err(MagicError::Decoding("missing version".to_string())),
+        }
+    } else {
+        Ok((0, bytes))
+    }
+}
+
+pub fn encode<T: Versioned>(record: &T) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(T::version());
+    bytes.append(
+        &mut bincode::serialize(record)
+            .unwrap_or_else(|_| panic!("could not serialize {}?", T::KIND)),
+    );
+    bytes
+}
+
+/// Decodes a stored record, upgrading it first if it's old.
+pub fn decode<T: Versioned>(bytes: &[u8]) -> Result<T, MagicError> {
+    let (version, payload) = version_of(bytes)?;
+    let payload = upgrade_payload::<T>(version, payload)?;
+
+    bincode::deserialize(&payload)
+        .map_err(|e| MagicError::Decoding(format!("bad {} state: {}", T::KIND, e)))
+}
+
+/// Returns the record re-encoded at the current version, or `None` if it
+/// already is.
+pub fn upgrade<T: Versioned>(bytes: &[u8]) -> Result<Option<Vec<u8>>, MagicError> {
+    let (version, _) = version_of(bytes)?;
+
+    if version == T::version() {
+        Ok(None)
+    } else {
+        // a full decode, so we don't write back something we can't read
+        Ok(Some(encode(&decode::<T>(bytes)?)))
+    }
+}
+
+fn upgrade_payload<T: Versioned>(version: u8, payload: &[u8]) -> Result<Vec<u8>, MagicError> {
+    if version > T::version() {
+        return Err(MagicError::Decoding(format!(
+            "{} is from a newer version ({} > {})",
+            T::KIND,
+            version,
+            T::version()
+        )));
+    }
+
+    T::MIGRATIONS[version as usize..]
+        .iter()
+        .try_fold(payload.to_vec(), |payload, upgrade| upgrade(&payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Lobby, Membership};
+
+    // what the first release wrote: untagged bincode for lobbies, and the bare
+    // channel id for players
+    const OLD_LOBBY: &[u8] = &[
+        3, 0, 0, 0, 0, 0, 0, 0, b'a', b'n', b'n', // creator
+        2, 0, 0, 0, 0, 0, 0, 0, // two players
+        3, 0, 0, 0, 0, 0, 0, 0, b'a', b'n', b'n', // the first
+        2, 0, 0, 0, 0, 0, 0, 0, b'b', b'o', // the second
+    ];
+    const OLD_MEMBERSHIP: &[u8] = b"channel";
+
+    #[test]
+    fn old_lobbies_are_upgraded() {
+        assert_eq!(version_of(OLD_LOBBY).unwrap().0, 0);
+
+        let lobby: Lobby = decode(OLD_LOBBY).unwrap();
+        assert_eq!(lobby.creator, "ann");
+        assert_eq!(lobby.players, vec!["ann", "bo"]);
+        assert_eq!(lobby.guild_id, "");
+        assert!(lobby.last_active > 0);
+        assert_eq!(lobby.game_id, "");
+
+        // and upgrading writes back what we just read
+        let upgraded = upgrade::<Lobby>(OLD_LOBBY).unwrap().unwrap();
+        assert_eq!(version_of(&upgraded).unwrap().0, Lobby::version());
+        let again: Lobby = decode(&upgraded).unwrap();
+        assert_eq!(again, lobby);
+        assert!(upgrade::<Lobby>(&upgraded).unwrap().is_none());
+    }
+
+    #[test]
+    fn old_memberships_are_upgraded() {
+        let membership: Membership = decode(OLD_MEMBERSHIP).unwrap();
+        assert_eq!(membership.lobby_id, "channel");
+
+        let upgraded = upgrade::<Membership>(OLD_MEMBERSHIP).unwrap().unwrap();
+        assert_eq!(version_of(&upgraded).unwrap().0, Membership::version());
+        let again: Membership = decode(&upgraded).unwrap();
+        assert_eq!(again.lobby_id, "channel");
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(Lobby::version() + 1);
+        bytes.extend_from_slice(OLD_LOBBY);
+
+        match decode::<Lobby>(&bytes) {
+            Err(MagicError::Decoding(message)) => assert!(message.contains("newer version")),
+            other => panic!("expected a decoding error, got {:?}", other),
+        }
+        assert!(upgrade::<Lobby>(&bytes).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::achievements::{self, Unlocked};
+use crate::database::Scan;
+use crate::encoding::Versioned;
+use crate::history::{self, ChannelGame, GameSummary, LoggedEvent, Unfinished};
+use crate::leaderboard::{self, Season, Standing};
+use crate::rating::{self, Ratings};
+use crate::settings::{self, GuildSettings};
+use crate::state::{self, Snapshot};
+use crate::stats::{self, PlayerStats};
+use crate::{Database, Lobby, MagicError};
+
+/// Everything in the database, in a form people can read. Trees added later
+/// should be `#[serde(default)]` so older dumps still load.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Dump {
+    pub lobbies: BTreeMap<String, Lobby>,
+    /// Player id to the channel id of their lobby.
+    pub players: BTreeMap<String, String>,
+    #[serde(default)]
+    pub guilds: BTreeMap<String, GuildSettings>,
+    #[serde(default)]
+    pub stats: BTreeMap<String, PlayerStats>,
+    #[serde(default)]
+    pub standings: BTreeMap<String, Standing>,
+    #[serde(default)]
+    pub seasons: BTreeMap<String, Season>,
+    #[serde(default)]
+    pub ratings: BTreeMap<String, Ratings>,
+    #[serde(default)]
+    pub games: BTreeMap<String, GameSummary>,
+    #[serde(default)]
+    pub events: BTreeMap<String, LoggedEvent>,
+    #[serde(default)]
+    pub channel_games: BTreeMap<String, ChannelGame>,
+    #[serde(default)]
+    pub achievements: BTreeMap<String, Unlocked>,
+}
+
+impl Dump {
+    /// How many records there are besides the lobby state.
+    pub fn records(&self) -> usize {
+        self.guilds.len()
+            + self.stats.len()
+            + self.standings.len()
+            + self.seasons.len()
+            + self.ratings.len()
+            + self.games.len()
+            + self.events.len()
+            + self.channel_games.len()
+            + self.achievements.len()
+    }
+}
+
+/// Everything in the database that can still be read. Records that can't be
+/// decoded are left out with a warning, so one of them doesn't cost every
+/// snapshot from then on; `magic db check` has more to say about them.
+pub fn export(db: &Database) -> Result<Dump, MagicError> {
+    Ok(Dump {
+        lobbies: readable("lobbies", db.scan_lobbies()?),
+        players: readable("players", db.scan_players()?),
+        guilds: records(db, settings::TREE)?,
+        stats: records(db, stats::TREE)?,
+        standings: records(db, leaderboard::STANDINGS_TREE)?,
+        seasons: records(db, leaderboard::SEASONS_TREE)?,
+        ratings: records(db, rating::TREE)?,
+        games: records(db, history::GAMES_TREE)?,
+        events: records(db, history::EVENTS_TREE)?,
+        channel_games: records(db, history::CHANNEL_GAMES_TREE)?,
+        achievements: records(db, achievements::TREE)?,
+    })
+}
+
+fn records<T: Versioned>(db: &Database, tree: &str) -> Result<BTreeMap<String, T>, MagicError> {
+    Ok(readable(tree, db.scan(tree)?))
+}
+
+fn readable<T>(tree: &str, scan: Scan<T>) -> BTreeMap<String, T> {
+    scan.into_iter()
+        .filter_map(|(key, record)| match record {
+            Ok(record) => Some((key, record)),
+            Err(e) => {
+                tracing::warn!(tree, %key, error = %e, "left an unreadable record out");
+                None
+            }
+        })
+        .collect()
+}
+
+fn put_records(db: &Database, dump: &Dump) -> Result<(), MagicError> {
+    put_tree(db, settings::TREE, &dump.guilds)?;
+    put_tree(db, stats::TREE, &dump.stats)?;
+    put_tree(db, leaderboard::STANDINGS_TREE, &dump.standings)?;
+    put_tree(db, leaderboard::SEASONS_TREE, &dump.seasons)?;
+    put_tree(db, rating::TREE, &dump.ratings)?;
+    put_tree(db, history::GAMES_TREE, &dump.games)?;
+    put_tree(db, history::EVENTS_TREE, &dump.events)?;
+    put_tree(db, history::CHANNEL_GAMES_TREE, &dump.channel_games)?;
+    history::index_unfinished(db)?;
+    put_tree(db, achievements::TREE, &dump.achievements)
+}
+
+fn put_tree<T: Versioned>(
+    db: &Database,
+    tree: &str,
+    records: &BTreeMap<String, T>,
+) -> Result<(), MagicError> {
+    for (key, record) in records {
+        db.put(tree, key, record)?;
+    }
+
+    Ok(())
+}
+
+fn clear_records(db: &Database) -> Result<(), MagicError> {
+    clear_tree::<GuildSettings>(db, settings::TREE)?;
+    clear_tree::<PlayerStats>(db, stats::TREE)?;
+    clear_tree::<Standing>(db, leaderboard::STANDINGS_TREE)?;
+    clear_tree::<Season>(db, leaderboard::SEASONS_TREE)?;
+    clear_tree::<Ratings>(db, rating::TREE)?;
+    clear_tree::<GameSummary>(db, history::GAMES_TREE)?;
+    clear_tree::<LoggedEvent>(db, history::EVENTS_TREE)?;
+    clear_tree::<ChannelGame>(db, history::CHANNEL_GAMES_TREE)?;
+    clear_tree::<Unlocked>(db, achievements::TREE)?;
+    // these aren't exported, since they're rebuilt from the events anyway
+    clear_tree::<Snapshot>(db, state::SNAPSHOTS_TREE)?;
+    // and this from the games
+    clear_tree::<Unfinished>(db, history::UNFINISHED_TREE)
+}
+
+fn clear_tree<T: Versioned>(db: &Database, tree: &str) -> Result<(), MagicError> {
+    // unreadable records go too, so this works on a broken database
+    for (key, _) in db.scan::<T>(tree)? {
+        db.remove(tree, &key)?;
+    }
+
+    Ok(())
+}
+
+/// Loads a dump into an empty database. Lobby state goes in all at once, the
+/// rest record by record.
+pub fn import(db: &Database, dump: &Dump) -> Result<(), MagicError> {
+    if !db.is_empty()? {
+        return Err(MagicError::NotEmpty);
+    }
+
+    db.transaction(|tx| {
+        for (lobby_id, lobby) in &dump.lobbies {
+            tx.set_lobby(lobby_id, lobby)?;
+        }
+        for (player_id, lobby_id) in &dump.players {
+            tx.set_player_lobby(player_id, lobby_id)?;
+        }
+
+        Ok(())
+    })?;
+
+    put_records(db, dump)
+}
+
+/// Replaces everything in the database with a dump. Like `import`, lobby
+/// state is swapped all at once and the rest record by record, so this isn't
+/// one transaction: if it fails partway, the lobbies are the dump's but the
+/// other records can be a mix of old and new. Restoring the same dump again
+/// finishes the job, since it clears every tree before filling it.
+pub fn restore(db: &Database, dump: &Dump) -> Result<(), MagicError> {
+    // unreadable ones go too, so this works on a broken database
+    let old_lobbies = db.scan_lobbies()?;
+    let old_players = db.scan_players()?;
+
+    db.transaction(|tx| {
+        for (lobby_id, _) in &old_lobbies {
+            tx.remove_lobby(lobby_id)?;
+        }
+        for (player_id, _) in &old_players {
+            tx.remove_player(player_id)?;
+        }
+
+        for (lobby_id, lobby) in &dump.lobbies {
+            tx.set_lobby(lobby_id, lobby)?;
+        }
+        for (player_id, lobby_id) in &dump.players {
+            tx.set_player_lobby(player_id, lobby_id)?;
+        }
+
+        Ok(())
+    })?;
+
+    clear_records(db)?;
+    put_records(db, dump)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unreadable_records_are_left_out() {
+        let sled = sled::Config::new().temporary(true).open().unwrap();
+        let db = Database::make(sled.clone());
+
+        db.put(stats::TREE, "fine", &PlayerStats::default())
+            .unwrap();
+        sled.open_tree(stats::TREE)
+            .unwrap()
+            .insert("broken", &b"not a record"[..])
+            .unwrap();
+        sled.open_tree("lobbies")
+            .unwrap()
+            .insert("broken", &b"not a lobby"[..])
+            .unwrap();
+
+        let dump = export(&db).unwrap();
+        assert!(dump.stats.contains_key("fine"));
+        assert!(!dump.stats.contains_key("broken"));
+        assert!(dump.lobbies.is_empty());
+    }
+}
@@ -0,0 +1,614 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::database::{Lobby, LobbyTransaction, TxError};
+use crate::history::{Event, LoggedEvent};
+use crate::replay::Replay;
+use crate::{achievements, history, leaderboard, rating, state, stats, Database, MagicError};
+
+/// A magician, an oracle and someone to suspect.
+pub const MIN_PLAYERS: usize = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Role {
+    Villager,
+    Oracle,
+    Magician,
+}
+
+impl Role {
+    pub const ALL: [Self; 3] = [Self::Villager, Self::Oracle, Self::Magician];
+
+    pub fn faction(self) -> Faction {
+        match self {
+            Self::Villager | Self::Oracle => Faction::Village,
+            Self::Magician => Faction::Magician,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Villager => write!(f, "villager"),
+            Self::Oracle => write!(f, "oracle"),
+            Self::Magician => write!(f, "magician"),
+        }
+    }
+}
+
+/// Who wins together.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Faction {
+    Village,
+    Magician,
+}
+
+impl Faction {
+    pub const ALL: [Self; 2] = [Self::Village, Self::Magician];
+}
+
+impl fmt::Display for Faction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Village => write!(f, "village"),
+            Self::Magician => write!(f, "magician"),
+        }
+    }
+}
+
+/// What time it is in a game.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// The magician experiments and the oracle reads.
+    Night,
+    /// Everyone votes on who to banish.
+    Day,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Night => write!(f, "night"),
+            Self::Day => write!(f, "day"),
+        }
+    }
+}
+
+/// How one player's game went.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlayerResult {
+    pub user_id: String,
+    pub role: Role,
+    pub won: bool,
+    pub banished: bool,
+    pub times_experimented_on: u64,
+    /// Only the oracle gets these.
+    pub correct_reads: u64,
+}
+
+/// Everything worth remembering about a game once it's over.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameResult {
+    pub game_id: String,
+    pub guild_id: String,
+    pub channel_id: String,
+    pub players: Vec<PlayerResult>,
+}
+
+/// Where a game is at, from its events. Before roles are handed out it's
+/// still just a lobby.
+#[derive(Debug, Clone, Default)]
+pub struct Game {
+    pub roles: BTreeMap<String, Role>,
+    /// `None` until the game starts.
+    pub phase: Option<Phase>,
+    pub banished: BTreeSet<String>,
+    /// Who the magician experimented on last night. They sit out the vote
+    /// the day after.
+    pub experimented: Option<String>,
+    /// Who the oracle read tonight.
+    pub read: Option<String>,
+    /// Today's votes, voter to target. Changing your mind replaces your vote.
+    pub votes: BTreeMap<String, String>,
+    /// Whether today's vote has already banished someone.
+    pub decided: bool,
+    pub times_experimented_on: BTreeMap<String, u64>,
+    pub correct_reads: u64,
+}
+
+impl Game {
+    pub fn from_events(events: &[LoggedEvent]) -> Self {
+        let mut game = Self::default();
+        for logged in events {
+            game.apply(&logged.event);
+        }
+        game
+    }
+
+    /// The game in `lobby`, read as part of `tx`.
+    pub fn load(tx: &dyn LobbyTransaction, lobby: &Lobby) -> Result<Self, TxError> {
+        Ok(Self::from_events(&history::events_in(tx, &lobby.game_id)?))
+    }
+
+    pub fn apply(&mut self, event: &Event) {
+        match event {
+            Event::RolesAssigned { roles } => self.roles = roles.clone(),
+            Event::PhaseChanged { phase } => {
+                self.phase = Some(*phase);
+                if *phase == Phase::Night {
+                    self.experimented = None;
+                    self.read = None;
+                    self.votes.clear();
+                    self.decided = false;
+                }
+            }
+            Event::Experimented { target, .. } => {
+                self.experimented = Some(target.clone());
+                *self
+                    .times_experimented_on
+                    .entry(target.clone())
+                    .or_default() += 1;
+            }
+            Event::Read { target, .. } => {
+                self.read = Some(target.clone());
+                if self.role(target) == Some(Role::Magician) {
+                    self.correct_reads += 1;
+                }
+            }
+            Event::Voted { voter, target } => {
+                self.votes.insert(voter.clone(), target.clone());
+            }
+            Event::Banished { user_id } => {
+                self.banished.insert(user_id.clone());
+                self.decided = true;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn started(&self) -> bool {
+        self.phase.is_some()
+    }
+
+    pub fn role(&self, user_id: &str) -> Option<Role> {
+        self.roles.get(user_id).copied()
+    }
+
+    /// Whether they have a role and haven't been banished.
+    pub fn in_play(&self, user_id: &str) -> bool {
+        self.roles.contains_key(user_id) && !self.banished.contains(user_id)
+    }
+
+    fn playing(&self) -> impl Iterator<Item = &String> {
+        self.roles
+            .keys()
+            .filter(move |user_id| !self.banished.contains(*user_id))
+    }
+
+    fn holder(&self, role: Role) -> Option<&String> {
+        self.playing()
+            .find(|user_id| self.role(user_id) == Some(role))
+    }
+
+    /// Whether they get a say in today's vote.
+    pub fn can_vote(&self, user_id: &str) -> bool {
+        self.in_play(user_id) && self.experimented.as_deref() != Some(user_id)
+    }
+
+    /// The village wins by banishing the magician, and the magician by
+    /// lasting until there's only one other player left.
+    pub fn winner(&self) -> Option<Faction> {
+        if !self.started() {
+            None
+        } else if self.holder(Role::Magician).is_none() {
+            Some(Faction::Village)
+        } else if self.playing().count() <= 2 {
+            Some(Faction::Magician)
+        } else {
+            None
+        }
+    }
+
+    /// Whoever has the most votes, unless it's a tie.
+    fn most_voted(&self) -> Option<&String> {
+        let mut tally = BTreeMap::new();
+        for target in self.votes.values() {
+            *tally.entry(target).or_insert(0) += 1;
+        }
+
+        let most = tally.values().copied().max()?;
+        let mut leaders = tally.into_iter().filter(|(_, votes)| *votes == most);
+        match (leaders.next(), leaders.next()) {
+            (Some((target, _)), None) => Some(target),
+            _ => None,
+        }
+    }
+
+    /// What happens next without anyone doing anything, because everyone
+    /// who had to act has. `None` if it's waiting on someone, or it's over.
+    pub fn next(&self) -> Option<Event> {
+        if self.winner().is_some() {
+            return None;
+        }
+
+        match self.phase? {
+            Phase::Night => {
+                let read = self.holder(Role::Oracle).is_none() || self.read.is_some();
+                (self.experimented.is_some() && read)
+                    .then_some(Event::PhaseChanged { phase: Phase::Day })
+            }
+            Phase::Day => {
+                let voted = self
+                    .playing()
+                    .filter(|user_id| self.can_vote(user_id))
+                    .all(|user_id| self.votes.contains_key(user_id));
+                if !voted {
+                    return None;
+                }
+
+                match self.most_voted() {
+                    Some(target) if !self.decided => Some(Event::Banished {
+                        user_id: target.clone(),
+                    }),
+                    _ => Some(Event::PhaseChanged {
+                        phase: Phase::Night,
+                    }),
+                }
+            }
+        }
+    }
+
+    /// How everyone's game went, once `winner` won it.
+    pub fn result(&self, lobby: &Lobby, channel_id: &str, winner: Faction) -> GameResult {
+        GameResult {
+            game_id: lobby.game_id.clone(),
+            guild_id: lobby.guild_id.clone(),
+            channel_id: channel_id.to_string(),
+            players: self
+                .roles
+                .iter()
+                .map(|(user_id, role)| PlayerResult {
+                    user_id: user_id.clone(),
+                    role: *role,
+                    won: role.faction() == winner,
+                    banished: self.banished.contains(user_id),
+                    times_experimented_on: self
+                        .times_experimented_on
+                        .get(user_id)
+                        .copied()
+                        .unwrap_or(0),
+                    correct_reads: if *role == Role::Oracle {
+                        self.correct_reads
+                    } else {
+                        0
+                    },
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Makes `event` happen in the game in `lobby_id`, then lets the game move
+/// on as far as it can without anyone else doing anything. Returns what
+/// happened after `event`. If that ended the game, it's up to the caller to
+/// `finish` it, since the results go outside the transaction.
+pub fn play(
+    tx: &dyn LobbyTransaction,
+    lobby_id: &str,
+    lobby: &Lobby,
+    game: &mut Game,
+    event: Event,
+) -> Result<Vec<Event>, TxError> {
+    game.apply(&event);
+    state::emit(tx, lobby_id, &lobby.game_id, event)?;
+
+    let mut happened = vec![];
+    while let Some(next) = game.next() {
+        game.apply(&next);
+        state::emit(tx, lobby_id, &lobby.game_id, next.clone())?;
+        happened.push(next);
+    }
+
+    Ok(happened)
+}
+
+/// Hands out roles: `magician` and `oracle` pick those out of `players` (and
+/// wrap around), and everyone else is a villager.
+pub fn assign_roles(players: &[String], magician: usize, oracle: usize) -> BTreeMap<String, Role> {
+    let magician = magician % players.len();
+    // skipping over the magician, so they can't be both
+    let oracle = (magician + 1 + oracle % (players.len() - 1)) % players.len();
+
+    players
+        .iter()
+        .enumerate()
+        .map(|(n, player)| {
+            let role = if n == magician {
+                Role::Magician
+            } else if n == oracle {
+                Role::Oracle
+            } else {
+                Role::Villager
+            };
+            (player.clone(), role)
+        })
+        .collect()
+}
+
+/// Where a game goes once it's over. Whatever keeps track of finished games
+/// hooks in here, so the game itself doesn't have to know about any of it.
+/// Returns the game's replay, for the end-of-game message to `announce`.
+///
+/// Only the first call for a game does anything: ending it in the history is
+/// what claims it, so a retry, or a game that already ended some other way,
+/// gets `None` and counts for nothing. If recording fails after that, the
+/// game goes uncounted rather than counted twice.
+pub fn finish(db: &Database, result: &GameResult) -> Result<Option<Replay>, MagicError> {
+    let ended = history::Event::Ended {
+        result: result.clone(),
+    };
+    let claimed = db.transaction(|tx| {
+        // games from before there was history can't be told apart from
+        // ones that already ended, so they don't count
+        match tx.get::<history::GameSummary>(history::GAMES_TREE, &result.game_id)? {
+            Some(summary) if summary.ended_at.is_none() => {}
+            _ => return Ok(false),
+        }
+
+        match tx.lobby(&result.channel_id)? {
+            // the game's over, so its lobby goes too
+            Some(lobby) if lobby.game_id == result.game_id => {
+                state::emit(tx, &result.channel_id, &result.game_id, ended.clone())?;
+            }
+            _ => {
+                let logged = history::LoggedEvent {
+                    at: crate::now(),
+                    event: ended.clone(),
+                };
+                history::append(tx, &result.game_id, &logged)?;
+            }
+        }
+
+        Ok(true)
+    })?;
+
+    if !claimed {
+        return Ok(None);
+    }
+
+    stats::record(db, result)?;
+    leaderboard::record(db, result)?;
+    rating::record(db, result)?;
+
+    let replay = Replay::build(db, &result.game_id)?;
+    let events = replay.as_ref().map(|replay| &replay.events[..]);
+    achievements::record(db, result, events)?;
+
+    Ok(replay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::Event;
+
+    fn database() -> Database {
+        Database::make(sled::Config::new().temporary(true).open().unwrap())
+    }
+
+    fn player(user_id: &str, role: Role, won: bool, banished: bool) -> PlayerResult {
+        PlayerResult {
+            user_id: user_id.to_string(),
+            role,
+            won,
+            banished,
+            times_experimented_on: 0,
+            correct_reads: 0,
+        }
+    }
+
+    fn result() -> GameResult {
+        GameResult {
+            game_id: "7".to_string(),
+            guild_id: "guild".to_string(),
+            channel_id: "channel".to_string(),
+            players: vec![
+                player("magician", Role::Magician, true, false),
+                player("villager", Role::Villager, false, true),
+                player("oracle", Role::Oracle, false, false),
+            ],
+        }
+    }
+
+    /// Plays the lobby for `result` up to the end, with the oracle voting
+    /// out the villager.
+    fn play(db: &Database, result: &GameResult) {
+        let events = vec![
+            Event::Created {
+                guild_id: result.guild_id.clone(),
+                channel_id: result.channel_id.clone(),
+                creator: "magician".to_string(),
+            },
+            Event::Joined {
+                user_id: "villager".to_string(),
+            },
+            Event::Joined {
+                user_id: "oracle".to_string(),
+            },
+            Event::Voted {
+                voter: "oracle".to_string(),
+                target: "villager".to_string(),
+            },
+        ];
+
+        for event in events {
+            db.transaction(|tx| {
+                state::emit(tx, &result.channel_id, &result.game_id, event.clone())
+            })
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn finishing_records_everything() {
+        let db = database();
+        let result = result();
+        play(&db, &result);
+
+        let replay = finish(&db, &result).unwrap().expect("the game had history");
+
+        let magician = stats::get(&db, "magician").unwrap();
+        assert_eq!(magician.games_played, 1);
+        assert_eq!(magician.total_wins(), 1);
+        assert_eq!(stats::get(&db, "villager").unwrap().times_banished, 1);
+
+        let season = leaderboard::current_season(&db, "guild").unwrap().number;
+        let standings =
+            leaderboard::top(&db, "guild", season, leaderboard::Category::Wins).unwrap();
+        assert_eq!(standings.len(), 3);
+        assert_eq!(standings[0].0, "magician");
+        assert_eq!(standings[0].1.magician_wins, 1);
+
+        let won = rating::get(&db, "magician").unwrap().get(Faction::Magician);
+        let lost = rating::get(&db, "oracle").unwrap().get(Faction::Village);
+        assert!(won > rating::INITIAL);
+        assert!(lost < rating::INITIAL);
+
+        let unlocked = achievements::get(&db, "magician").unwrap().achievements;
+        assert!(unlocked.contains_key("first-win"));
+        assert!(unlocked.contains_key("untouchable-magician"));
+        let unlocked = achievements::get(&db, "oracle").unwrap().achievements;
+        assert!(unlocked.contains_key("good-instincts"));
+        assert!(!unlocked.contains_key("first-win"));
+
+        let summary = history::summary(&db, "7").unwrap().unwrap();
+        assert!(matches!(summary.ending, Some(Event::Ended { .. })));
+        assert!(matches!(
+            replay.events.last().map(|logged| &logged.event),
+            Some(Event::Ended { .. })
+        ));
+
+        // and the lobby's gone
+        assert!(db.lobbies().unwrap().is_empty());
+        assert!(db.players().unwrap().is_empty());
+    }
+
+    #[test]
+    fn finishing_again_counts_for_nothing() {
+        let db = database();
+        let result = result();
+        play(&db, &result);
+
+        assert!(finish(&db, &result).unwrap().is_some());
+        assert!(finish(&db, &result).unwrap().is_none());
+        assert_eq!(stats::get(&db, "magician").unwrap().games_played, 1);
+
+        // and neither do games we can't tell apart from finished ones
+        let mut unknown = result.clone();
+        unknown.game_id = "8".to_string();
+        assert!(finish(&db, &unknown).unwrap().is_none());
+        assert_eq!(stats::get(&db, "magician").unwrap().games_played, 1);
+    }
+
+    fn roles(roles: &[(&str, Role)]) -> Event {
+        Event::RolesAssigned {
+            roles: roles
+                .iter()
+                .map(|(user_id, role)| (user_id.to_string(), *role))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn ties_banish_nobody_and_the_magician_outlasts_the_village() {
+        let mut game = Game::default();
+        for event in [
+            roles(&[
+                ("m", Role::Magician),
+                ("o", Role::Oracle),
+                ("v", Role::Villager),
+                ("w", Role::Villager),
+            ]),
+            Event::PhaseChanged {
+                phase: Phase::Night,
+            },
+            Event::Experimented {
+                magician: "m".to_string(),
+                target: "v".to_string(),
+            },
+        ] {
+            game.apply(&event);
+        }
+
+        // still waiting on the oracle
+        assert!(game.next().is_none());
+        game.apply(&Event::Read {
+            oracle: "o".to_string(),
+            target: "w".to_string(),
+        });
+        assert!(matches!(
+            game.next(),
+            Some(Event::PhaseChanged { phase: Phase::Day })
+        ));
+        game.apply(&game.next().unwrap());
+
+        assert!(!game.can_vote("v"));
+        let vote = |game: &mut Game, voter: &str, target: &str| {
+            game.apply(&Event::Voted {
+                voter: voter.to_string(),
+                target: target.to_string(),
+            })
+        };
+        vote(&mut game, "m", "o");
+        vote(&mut game, "o", "m");
+        assert!(game.next().is_none());
+        vote(&mut game, "w", "v");
+        // one each, so nobody goes
+        assert!(matches!(
+            game.next(),
+            Some(Event::PhaseChanged {
+                phase: Phase::Night
+            })
+        ));
+
+        game.apply(&Event::Banished {
+            user_id: "o".to_string(),
+        });
+        assert_eq!(game.winner(), None);
+        game.apply(&Event::Banished {
+            user_id: "w".to_string(),
+        });
+        assert_eq!(game.winner(), Some(Faction::Magician));
+        assert!(game.next().is_none());
+
+        let lobby = Lobby {
+            creator: "m".to_string(),
+            players: vec![],
+            guild_id: "guild".to_string(),
+            last_active: 0,
+            game_id: "7".to_string(),
+        };
+        let result = game.result(&lobby, "channel", Faction::Magician);
+        let oracle = result.players.iter().find(|player| player.user_id == "o");
+        assert!(oracle.unwrap().banished);
+        assert_eq!(game.times_experimented_on.get("v"), Some(&1));
+    }
+
+    #[test]
+    fn roles_go_to_different_players() {
+        let players = ["a", "b", "c"].map(str::to_string);
+
+        for magician in 0..6 {
+            for oracle in 0..6 {
+                let roles = assign_roles(&players, magician, oracle);
+                let count = |role| roles.values().filter(|other| **other == role).count();
+                assert_eq!(count(Role::Magician), 1);
+                assert_eq!(count(Role::Oracle), 1);
+                assert_eq!(count(Role::Villager), 1);
+            }
+        }
+    }
+}
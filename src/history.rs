@@ -0,0 +1,383 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::database::{LobbyTransaction, TxError};
+use crate::encoding::{Upgrade, Versioned};
+use crate::game::{GameResult, Phase, Role};
+use crate::{Database, MagicError};
+
+/// One summary per game, keyed by game id.
+pub const GAMES_TREE: &str = "games";
+/// Every game's events, keyed by `game/index`.
+pub const EVENTS_TREE: &str = "events";
+/// Games by the channel they were in, keyed by `channel/game`.
+pub const CHANNEL_GAMES_TREE: &str = "channel_games";
+/// Games that haven't ended, keyed by game id, so they can be counted
+/// without reading every summary.
+pub const UNFINISHED_TREE: &str = "unfinished_games";
+
+const SHOWN: usize = 10;
+
+/// Something that happened in a game. These are only ever appended.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Event {
+    Created {
+        guild_id: String,
+        channel_id: String,
+        creator: String,
+    },
+    Joined {
+        user_id: String,
+    },
+    Left {
+        user_id: String,
+    },
+    /// Someone with Manage Messages took the lobby over.
+    Hijacked {
+        user_id: String,
+    },
+    RolesAssigned {
+        roles: BTreeMap<String, Role>,
+    },
+    PhaseChanged {
+        phase: Phase,
+    },
+    Voted {
+        voter: String,
+        target: String,
+    },
+    Experimented {
+        magician: String,
+        target: String,
+    },
+    Ended {
+        result: GameResult,
+    },
+    /// The creator left, taking the lobby with them.
+    Disbanded,
+    /// Nobody did anything for too long.
+    Expired,
+    /// The lobby vanished without telling us, found by the checker.
+    Abandoned,
+    /// The oracle looked into whether `target` is the magician.
+    Read {
+        oracle: String,
+        target: String,
+    },
+    /// The day's vote went against them, so they're out of the game.
+    Banished {
+        user_id: String,
+    },
+}
+
+impl Event {
+    /// Whether the game is over once this happens.
+    pub fn is_ending(&self) -> bool {
+        matches!(
+            self,
+            Self::Ended { .. } | Self::Disbanded | Self::Expired | Self::Abandoned
+        )
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Created { creator, .. } => write!(f, "<@{}> made the lobby", creator),
+            Self::Joined { user_id } => write!(f, "<@{}> joined", user_id),
+            Self::Left { user_id } => write!(f, "<@{}> left", user_id),
+            Self::Hijacked { user_id } => write!(f, "<@{}> hijacked the lobby", user_id),
+            Self::RolesAssigned { roles } => write!(f, "{} roles were handed out", roles.len()),
+            Self::PhaseChanged { phase } => write!(f, "{} fell", phase),
+            Self::Voted { voter, target } => write!(f, "<@{}> voted for <@{}>", voter, target),
+            Self::Experimented { magician, target } => {
+                write!(f, "<@{}> experimented on <@{}>", magician, target)
+            }
+            Self::Ended { .. } => write!(f, "the game ended"),
+            Self::Disbanded => write!(f, "the lobby was disbanded"),
+            Self::Expired => write!(f, "the lobby was closed for being idle"),
+            Self::Abandoned => write!(f, "the lobby went missing"),
+            Self::Read { oracle, target } => write!(f, "<@{}> read <@{}>", oracle, target),
+            Self::Banished { user_id } => write!(f, "<@{}> was banished", user_id),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoggedEvent {
+    /// In unix seconds.
+    pub at: u64,
+    pub event: Event,
+}
+
+impl Versioned for LoggedEvent {
+    const KIND: &'static str = "event";
+    const MIGRATIONS: &'static [Upgrade] = &[];
+}
+
+/// What a game's events add up to, so listing games doesn't mean reading
+/// every one of them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameSummary {
+    pub guild_id: String,
+    pub channel_id: String,
+    pub creator: String,
+    pub started_at: u64,
+    pub ended_at: Option<u64>,
+    /// Everyone who was ever in it.
+    pub players: Vec<String>,
+    pub events: u64,
+    /// The last event, if it was one that ends the game.
+    pub ending: Option<Event>,
+}
+
+impl Versioned for GameSummary {
+    const KIND: &'static str = "game";
+    const MIGRATIONS: &'static [Upgrade] = &[];
+}
+
+impl GameSummary {
+    pub fn describe_ending(&self) -> String {
+        match &self.ending {
+            None => "still going".to_string(),
+            Some(Event::Ended { result }) => {
+                let winners = result
+                    .players
+                    .iter()
+                    .filter(|player| player.won)
+                    .map(|player| format!("<@{}> ({})", player.user_id, player.role))
+                    .collect::<Vec<_>>();
+
+                if winners.is_empty() {
+                    "nobody won".to_string()
+                } else {
+                    format!("won by {}", winners.join(", "))
+                }
+            }
+            Some(Event::Disbanded) => "disbanded".to_string(),
+            Some(Event::Expired) => "closed for being idle".to_string(),
+            Some(_) => "abandoned".to_string(),
+        }
+    }
+}
+
+/// Which game a `channel_games` entry points at, since the key pads it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChannelGame {
+    pub game_id: String,
+}
+
+impl Versioned for ChannelGame {
+    const KIND: &'static str = "channel game";
+    const MIGRATIONS: &'static [Upgrade] = &[];
+}
+
+/// An `unfinished_games` entry. The key is what matters.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Unfinished {
+    pub started_at: u64,
+}
+
+impl Versioned for Unfinished {
+    const KIND: &'static str = "unfinished game";
+    const MIGRATIONS: &'static [Upgrade] = &[];
+}
+
+fn event_key(game_id: &str, index: u64) -> String {
+    // padded so they sort in order
+    format!("{}/{:010}", game_id, index)
+}
+
+fn channel_key(channel_id: &str, game_id: &str) -> String {
+    // game ids are snowflakes, so padding them sorts games by age
+    format!("{}/{:0>20}", channel_id, game_id)
+}
+
+/// Records an event that's happening right now. For events that change the
+/// lobby, use `state::emit` instead, which records them too.
+pub fn record(db: &Database, game_id: &str, event: Event) -> Result<(), MagicError> {
+    let logged = LoggedEvent {
+        at: crate::now(),
+        event,
+    };
+
+    db.transaction(|tx| append(tx, game_id, &logged))?;
+    Ok(())
+}
+
+/// Appends an event to a game's log, returning where it went. `Created`
+/// starts a new game, anything else for a game we don't know about is
+/// dropped. That's either a lobby from before there was history, or the game
+/// already ended.
+pub fn append(
+    tx: &dyn LobbyTransaction,
+    game_id: &str,
+    logged: &LoggedEvent,
+) -> Result<Option<u64>, TxError> {
+    if game_id.is_empty() {
+        return Ok(None);
+    }
+
+    let mut summary = match (tx.get::<GameSummary>(GAMES_TREE, game_id)?, &logged.event) {
+        (
+            None,
+            Event::Created {
+                guild_id,
+                channel_id,
+                creator,
+            },
+        ) => {
+            tx.put(
+                CHANNEL_GAMES_TREE,
+                &channel_key(channel_id, game_id),
+                &ChannelGame {
+                    game_id: game_id.to_string(),
+                },
+            )?;
+            tx.put(
+                UNFINISHED_TREE,
+                game_id,
+                &Unfinished {
+                    started_at: logged.at,
+                },
+            )?;
+
+            GameSummary {
+                guild_id: guild_id.clone(),
+                channel_id: channel_id.clone(),
+                creator: creator.clone(),
+                started_at: logged.at,
+                ended_at: None,
+                players: vec![creator.clone()],
+                events: 0,
+                ending: None,
+            }
+        }
+        (Some(summary), _) if summary.ended_at.is_none() => summary,
+        _ => return Ok(None),
+    };
+
+    let index = summary.events;
+    summary.events += 1;
+
+    match &logged.event {
+        Event::Joined { user_id } | Event::Hijacked { user_id }
+            if !summary.players.contains(user_id) =>
+        {
+            summary.players.push(user_id.clone());
+        }
+        ending if ending.is_ending() => {
+            summary.ended_at = Some(logged.at);
+            summary.ending = Some(ending.clone());
+            tx.remove_record(UNFINISHED_TREE, game_id)?;
+        }
+        _ => {}
+    }
+
+    tx.put(GAMES_TREE, game_id, &summary)?;
+    tx.put(EVENTS_TREE, &event_key(game_id, index), logged)?;
+
+    Ok(Some(index))
+}
+
+/// Brings `unfinished_games` in line with the summaries, for games from
+/// before it was kept and after an import. Returns how many entries changed.
+pub fn index_unfinished(db: &Database) -> Result<usize, MagicError> {
+    // a game that can't be read isn't counted
+    let unfinished: BTreeMap<String, u64> = db
+        .scan::<GameSummary>(GAMES_TREE)?
+        .into_iter()
+        .filter_map(|(game_id, summary)| match summary {
+            Ok(summary) if summary.ended_at.is_none() => Some((game_id, summary.started_at)),
+            _ => None,
+        })
+        .collect();
+    let indexed: BTreeSet<String> = db
+        .scan::<Unfinished>(UNFINISHED_TREE)?
+        .into_iter()
+        .map(|(game_id, _)| game_id)
+        .collect();
+
+    let mut changed = 0;
+    for (game_id, &started_at) in &unfinished {
+        if !indexed.contains(game_id) {
+            db.put(UNFINISHED_TREE, game_id, &Unfinished { started_at })?;
+            changed += 1;
+        }
+    }
+    for game_id in &indexed {
+        if !unfinished.contains_key(game_id) {
+            db.remove(UNFINISHED_TREE, game_id)?;
+            changed += 1;
+        }
+    }
+
+    Ok(changed)
+}
+
+/// A game's events, oldest first, read as part of `tx`.
+pub fn events_in(tx: &dyn LobbyTransaction, game_id: &str) -> Result<Vec<LoggedEvent>, TxError> {
+    let count = match tx.get::<GameSummary>(GAMES_TREE, game_id)? {
+        Some(summary) => summary.events,
+        None => return Ok(vec![]),
+    };
+
+    let mut events = vec![];
+    for index in 0..count {
+        if let Some(logged) = tx.get(EVENTS_TREE, &event_key(game_id, index))? {
+            events.push(logged);
+        }
+    }
+
+    Ok(events)
+}
+
+pub fn summary(db: &Database, game_id: &str) -> Result<Option<GameSummary>, MagicError> {
+    db.get(GAMES_TREE, game_id)
+}
+
+/// A game's events, oldest first.
+pub fn events(db: &Database, game_id: &str) -> Result<Vec<LoggedEvent>, MagicError> {
+    events_from(db, game_id, 0)
+}
+
+/// A game's events from the `index`th one on.
+pub fn events_from(
+    db: &Database,
+    game_id: &str,
+    index: u64,
+) -> Result<Vec<LoggedEvent>, MagicError> {
+    let prefix = format!("{}/", game_id);
+    db.scan_prefix_from(EVENTS_TREE, &prefix, &event_key(game_id, index))?
+        .into_iter()
+        .map(|(_, event)| event)
+        .collect()
+}
+
+/// The newest games in a channel, newest first.
+pub fn recent_games(
+    db: &Database,
+    channel_id: &str,
+) -> Result<Vec<(String, GameSummary)>, MagicError> {
+    let prefix = format!("{}/", channel_id);
+    let mut games = vec![];
+
+    for (_, entry) in db
+        .scan_prefix::<ChannelGame>(CHANNEL_GAMES_TREE, &prefix)?
+        .into_iter()
+        .rev()
+    {
+        let game_id = entry?.game_id;
+
+        if let Some(summary) = summary(db, &game_id)? {
+            games.push((game_id, summary));
+        }
+        if games.len() == SHOWN {
+            break;
+        }
+    }
+
+    Ok(games)
+}
@@ -0,0 +1,166 @@
+use std::cmp::Reverse;
+
+use serde::{Deserialize, Serialize};
+
+use crate::encoding::{Upgrade, Versioned};
+use crate::game::{GameResult, Role};
+use crate::{Database, MagicError};
+
+/// Per guild, per season standings, keyed by `guild/season/user`. Keeping the
+/// guild up front means a leaderboard only reads that guild's players.
+pub const STANDINGS_TREE: &str = "standings";
+/// Which season each guild is on, keyed by guild.
+pub const SEASONS_TREE: &str = "seasons";
+
+/// Nobody tops the win rate board off of one lucky game.
+pub const MIN_GAMES_FOR_RATE: u64 = 5;
+
+const SHOWN: usize = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Standing {
+    pub games: u64,
+    pub wins: u64,
+    pub magician_games: u64,
+    pub magician_wins: u64,
+}
+
+impl Versioned for Standing {
+    const KIND: &'static str = "standing";
+    const MIGRATIONS: &'static [Upgrade] = &[];
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Season {
+    pub number: u32,
+    /// In unix seconds, or 0 for the first season, which started whenever.
+    pub started_at: u64,
+}
+
+impl Default for Season {
+    fn default() -> Self {
+        Self {
+            number: 1,
+            started_at: 0,
+        }
+    }
+}
+
+impl Versioned for Season {
+    const KIND: &'static str = "season";
+    const MIGRATIONS: &'static [Upgrade] = &[];
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Category {
+    Wins,
+    WinRate,
+    Magician,
+}
+
+impl Category {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "wins" => Some(Self::Wins),
+            "win-rate" => Some(Self::WinRate),
+            "magician" => Some(Self::Magician),
+            _ => None,
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::Wins => "most wins",
+            Self::WinRate => "best win rate",
+            Self::Magician => "best magician",
+        }
+    }
+}
+
+fn standing_key(guild_id: &str, season: u32, user_id: &str) -> String {
+    format!("{}/{}/{}", guild_id, season, user_id)
+}
+
+pub fn current_season(db: &Database, guild_id: &str) -> Result<Season, MagicError> {
+    Ok(db.get(SEASONS_TREE, guild_id)?.unwrap_or_default())
+}
+
+/// Archives the current season (its standings stay where they are) and
+/// starts the next one.
+pub fn start_season(db: &Database, guild_id: &str) -> Result<Season, MagicError> {
+    let season = db.update(SEASONS_TREE, guild_id, |season: Option<Season>| {
+        Some(Season {
+            number: season.unwrap_or_default().number + 1,
+            started_at: crate::now(),
+        })
+    })?;
+
+    Ok(season.expect("we just made a season"))
+}
+
+/// Adds a finished game to its guild's current season.
+pub fn record(db: &Database, result: &GameResult) -> Result<(), MagicError> {
+    let season = current_season(db, &result.guild_id)?.number;
+
+    for player in &result.players {
+        let key = standing_key(&result.guild_id, season, &player.user_id);
+
+        db.update(STANDINGS_TREE, &key, |standing: Option<Standing>| {
+            let mut standing = standing.unwrap_or_default();
+
+            standing.games += 1;
+            if player.won {
+                standing.wins += 1;
+            }
+            if player.role == Role::Magician {
+                standing.magician_games += 1;
+                if player.won {
+                    standing.magician_wins += 1;
+                }
+            }
+
+            Some(standing)
+        })?;
+    }
+
+    Ok(())
+}
+
+/// The top of a guild's season in some category, best first.
+pub fn top(
+    db: &Database,
+    guild_id: &str,
+    season: u32,
+    category: Category,
+) -> Result<Vec<(String, Standing)>, MagicError> {
+    let prefix = standing_key(guild_id, season, "");
+    let mut standings = db
+        .scan_prefix::<Standing>(STANDINGS_TREE, &prefix)?
+        .into_iter()
+        .map(|(key, standing)| Ok((key[prefix.len()..].to_string(), standing?)))
+        .collect::<Result<Vec<_>, MagicError>>()?;
+
+    match category {
+        Category::Wins => {
+            standings.sort_by_key(|(_, standing)| (Reverse(standing.wins), standing.games));
+        }
+        Category::WinRate => {
+            standings.retain(|(_, standing)| standing.games >= MIN_GAMES_FOR_RATE);
+            // compare wins/games without going through floats
+            standings.sort_by(|(_, a), (_, b)| {
+                (b.wins * a.games)
+                    .cmp(&(a.wins * b.games))
+                    .then(b.games.cmp(&a.games))
+            });
+        }
+        Category::Magician => {
+            standings.retain(|(_, standing)| standing.magician_games > 0);
+            standings.sort_by_key(|(_, standing)| {
+                (Reverse(standing.magician_wins), standing.magician_games)
+            });
+        }
+    }
+    standings.truncate(SHOWN);
+
+    Ok(standings)
+}
@@ -1,10 +1,36 @@
+pub mod achievements;
+pub mod check;
+pub mod config;
+pub mod database;
+pub mod discord;
+pub mod encoding;
+pub mod export;
+pub mod game;
+pub mod history;
+pub mod leaderboard;
+pub mod metrics;
+pub mod ratelimit;
+pub mod rating;
+pub mod replay;
 pub mod request_types;
 pub mod response_types;
+pub mod seen;
+pub mod settings;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod state;
+pub mod stats;
+pub mod sweeper;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod verify;
 
+use config::Command;
+pub use database::{Database, Lobby};
 use response_types::{Data, InteractionResponse};
-use serde::{Deserialize, Serialize};
-use sled::{transaction::ConflictableTransactionResult, Transactional};
-use std::convert::Infallible;
+use std::convert::TryFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{error::Error, fmt};
 
 #[derive(Debug)]
@@ -12,46 +38,53 @@ pub enum MagicError {
     WeirdHTTPError(String),
     StringConversion,
     JSONParsing(String),
+    /// Discord sent something that doesn't fit what the interaction should
+    /// look like.
+    MalformedInteraction(String),
+    /// A command came without its `data`, named by the command.
+    MissingData(&'static str),
+    /// A member's permissions weren't the integer discord promises.
+    BadPermissions(String),
+    /// A handler panicked. What it said went to the logs already.
+    Panicked,
     // error for things idk about yet
     GenericError,
     SledError,
+    Decoding(String),
+    /// Importing into a database that already has data in it.
+    NotEmpty,
+    IOError,
+    #[cfg(feature = "sqlite")]
+    SqliteError,
+    #[cfg(feature = "tls")]
+    TlsError(String),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Lobby {
-    creator: String,
-    players: Vec<String>,
+/// The current time, in unix seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("the clock is before 1970?")
+        .as_secs()
 }
 
-#[derive(Clone)]
-pub struct Database {
-    lobbies: sled::Tree,
-    players: sled::Tree,
-}
-
-fn encode_lobby(lobby: &Lobby) -> Vec<u8> {
-    bincode::serialize(lobby).expect("could not serialize lobby?")
-}
-
-fn decode_lobby(lobby: &[u8]) -> Lobby {
-    bincode::deserialize(lobby).expect("bad lobby state.")
-}
+impl Error for MagicError {}
 
-impl Database {
-    pub fn make(db: sled::Db) -> Self {
-        Self {
-            lobbies: db
-                .open_tree("lobbies")
-                .expect("was not able to open lobby tree"),
-            players: db
-                .open_tree("players")
-                .expect("was not able to open player tree"),
+impl MagicError {
+    /// What to answer with when this stops a request before it reaches a
+    /// handler. Some requests are just bad, the rest is on us.
+    pub fn status(&self) -> hyper::StatusCode {
+        match self {
+            Self::StringConversion
+            | Self::JSONParsing(_)
+            | Self::MalformedInteraction(_)
+            | Self::MissingData(_)
+            | Self::BadPermissions(_) => hyper::StatusCode::BAD_REQUEST,
+            _ => hyper::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
 
-impl Error for MagicError {}
-
 impl fmt::Display for MagicError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -63,22 +96,35 @@ impl fmt::Display for MagicError {
                 "An error occurred while converting your body to a string."
             ),
             Self::JSONParsing(err) => write!(f, "{}", err),
+            Self::MalformedInteraction(err) => write!(f, "Malformed interaction: {}", err),
+            Self::MissingData(command) => write!(f, "{} came without `data`.", command),
+            Self::BadPermissions(permissions) => {
+                write!(f, "{:?} isn't a permissions integer.", permissions)
+            }
+            Self::Panicked => write!(f, "A handler panicked!"),
             Self::GenericError => write!(f, "An error occurred!"),
             Self::SledError => write!(f, "A filesystem error happened with sled!"),
+            Self::Decoding(err) => write!(f, "Could not read stored data: {}", err),
+            Self::NotEmpty => write!(f, "The database already has data in it!"),
+            Self::IOError => write!(f, "A filesystem error happened!"),
+            #[cfg(feature = "sqlite")]
+            Self::SqliteError => write!(f, "A database error happened with sqlite!"),
+            #[cfg(feature = "tls")]
+            Self::TlsError(err) => write!(f, "Could not set up TLS: {}", err),
         }
     }
 }
 
 impl From<hyper::Error> for MagicError {
     fn from(s: hyper::Error) -> Self {
-        eprintln!("Hyper error says: {:?}", s);
+        tracing::error!(error = ?s, "hyper error");
         Self::WeirdHTTPError("buffering body".to_string())
     }
 }
 
 impl From<std::str::Utf8Error> for MagicError {
     fn from(s: std::str::Utf8Error) -> Self {
-        eprintln!("String error says: {:?}", s);
+        tracing::error!(error = ?s, "body isn't utf-8");
         Self::StringConversion
     }
 }
@@ -89,23 +135,58 @@ impl From<serde_json::Error> for MagicError {
     }
 }
 
+impl From<std::io::Error> for MagicError {
+    fn from(s: std::io::Error) -> Self {
+        tracing::error!(error = ?s, "io error");
+        Self::IOError
+    }
+}
+
 impl From<sled::Error> for MagicError {
     fn from(s: sled::Error) -> Self {
-        eprintln!("sled error says: {:?}", s);
+        tracing::error!(error = ?s, "sled error");
         Self::SledError
     }
 }
 
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for MagicError {
+    fn from(s: rusqlite::Error) -> Self {
+        tracing::error!(error = ?s, "sqlite error");
+        Self::SqliteError
+    }
+}
+
+/// The `data` of a command, which discord always sends for them.
+fn command_data(
+    interaction: &request_types::Interaction,
+    command: &'static str,
+) -> Result<request_types::ApplicationCommandData, MagicError> {
+    interaction
+        .clone()
+        .data()
+        .ok_or(MagicError::MissingData(command))
+}
+
+/// Whether the member has the permission at `bit` (13 is MANAGE_MESSAGES, 5
+/// is MANAGE_GUILD).
+fn has_permission(interaction: &request_types::Interaction, bit: u32) -> Result<bool, MagicError> {
+    let permissions = interaction.clone().member().permissions();
+    let permissions = permissions
+        .parse::<u128>()
+        .map_err(|_| MagicError::BadPermissions(permissions))?;
+
+    Ok(permissions >> bit & 1 == 1)
+}
+
 fn create_lobby(
     interaction: request_types::Interaction,
     db: Database,
 ) -> Result<response_types::InteractionResponse, MagicError> {
-    let mut options: Vec<request_types::ApplicationCommandDataOption> = interaction
-        .clone()
-        .data()
-        .expect("create lobby missing `data`")
-        .options()
-        .unwrap_or_default();
+    let mut options: Vec<request_types::ApplicationCommandDataOption> =
+        command_data(&interaction, "create lobby")?
+            .options()
+            .unwrap_or_default();
 
     options.retain(|option| {
         match option {
@@ -122,37 +203,27 @@ fn create_lobby(
 
     let options: Vec<_> = options
         .iter()
-        .map(|option| {
-            if let request_types::ApplicationCommandDataOption::Value { value, .. } = option {
-                if let request_types::ApplicationCommandDataValue::Boolean(bool) = value {
-                    // get rid of that nasty reference (turns into a double reference with `Vec.get`)
-                    *bool
-                } else {
-                    panic!("impossible state.")
-                }
+        .filter_map(|option| {
+            if let request_types::ApplicationCommandDataOption::Value {
+                value: request_types::ApplicationCommandDataValue::Boolean(bool),
+                ..
+            } = option
+            {
+                // get rid of that nasty reference (turns into a double reference with `Vec.first`)
+                Some(*bool)
             } else {
-                panic!("impossible state.")
+                None
             }
         })
         .collect();
 
     let hijacking = options
-        .get(0)
+        .first()
         .map(|thing| thing.to_owned())
         .unwrap_or(false);
 
     // hijacking requires MANAGE_MESSAGES
-    if hijacking
-        && interaction
-            .clone()
-            .member()
-            .permissions()
-            .parse::<u128>()
-            .expect("bad permissions int")
-            >> 13
-            & 1
-            == 0
-    {
+    if hijacking && !has_permission(&interaction, 13)? {
         return Ok(InteractionResponse::create(
             3,
             Data::content(format!(
@@ -163,71 +234,70 @@ fn create_lobby(
     };
 
     let player_id = interaction.clone().member().user().id();
-    let result = (&db.lobbies, &db.players)
-        .transaction(|(lobbies, players)| {
-            let player = players.get(&player_id)?;
-            let lobby_id_val = interaction.clone().channel_id();
-            let lobby_id = lobby_id_val.as_str();
-            let cur_lobby = lobbies
-                .get(lobby_id)?
-                .map(|thing| decode_lobby(thing.as_ref()));
-
-            // if there's a lobby already...
-            // TODO: make this do some extra work for UX (leaving, etc.)
-            if let Some(id) = player {
-                // we do some extra work for error messages.
-                return if id == lobby_id {
-                    Ok(Ok("you're already in that lobby!"))
-                } else {
-                    Ok(Ok("you're in another lobby!"))
-                };
-            };
+    let guild_id = interaction.clone().guild_id();
+    // interaction ids are unique, so the one that made the lobby names the game
+    let game_id = interaction.id().clone();
+    let lobby_id_val = interaction.clone().channel_id();
+    let lobby_id = lobby_id_val.as_str();
+
+    let result = db.transaction(|tx| {
+        let player = tx.player_lobby(&player_id)?;
+        let cur_lobby = tx.lobby(lobby_id)?;
 
-            let hijacking = if cur_lobby.is_none() {
-                // it doesn't matter, let's simplify logic
-                false
+        // if there's a lobby already...
+        // TODO: make this do some extra work for UX (leaving, etc.)
+        if let Some(id) = player {
+            // we do some extra work for error messages.
+            return if id == lobby_id {
+                Ok("you're already in that lobby!")
             } else {
-                hijacking
+                Ok("you're in another lobby!")
             };
+        };
 
-            if cur_lobby.is_some() && !hijacking {
-                return Ok(Ok("a lobby already exists in this channel! try /join!"));
-            }
+        let hijacking = if cur_lobby.is_none() {
+            // it doesn't matter, let's simplify logic
+            false
+        } else {
+            hijacking
+        };
 
-            if player.is_some() {
-                // the player is in a lobby
-                // so... are they the owner of their old lobby?
-                // TODO: delete / leave old lobby
-                return Ok(Ok("tell a5 to do this."));
-            }
+        if cur_lobby.is_some() && !hijacking {
+            return Ok("a lobby already exists in this channel! try /join!");
+        }
 
-            if !hijacking {
-                lobbies.insert(
-                    lobby_id,
-                    encode_lobby(&Lobby {
-                        creator: player_id.clone(),
-                        players: vec![player_id.clone()],
-                    }),
-                )?;
-            } else {
-                let mut prev_players = cur_lobby.map(|thing| thing.players).unwrap_or_default();
-                prev_players.push(player_id.clone());
-
-                lobbies.insert(
-                    lobby_id,
-                    encode_lobby(&Lobby {
-                        creator: player_id.clone(),
-                        players: prev_players,
-                    }),
-                )?;
-            }
-            players.insert(player_id.as_str(), lobby_id)?;
+        if player.is_some() {
+            // the player is in a lobby
+            // so... are they the owner of their old lobby?
+            // TODO: delete / leave old lobby
+            return Ok("tell a5 to do this.");
+        }
 
-            ConflictableTransactionResult::<sled::Result<&'static str>, Infallible>::Ok(Ok(
-                "all systems are a go.",
-            ))
-        })
-        .expect("tx error")?;
+        if let Some(cur_lobby) = cur_lobby.filter(|_| hijacking) {
+            // it's still the same game, just with someone else in charge
+            state::emit(
+                tx,
+                lobby_id,
+                &cur_lobby.game_id,
+                history::Event::Hijacked {
+                    user_id: player_id.clone(),
+                },
+            )?;
+        } else {
+            state::emit(
+                tx,
+                lobby_id,
+                &game_id,
+                history::Event::Created {
+                    guild_id: guild_id.clone(),
+                    channel_id: lobby_id.to_string(),
+                    creator: player_id.clone(),
+                },
+            )?;
+        }
+
+        Ok("all systems are a go.")
+    })?;
 
     Ok(InteractionResponse::create(
         3,
@@ -244,148 +314,1123 @@ fn join_lobby(
     let lobby_id_val = interaction.channel_id();
     let lobby_id = lobby_id_val.as_str();
 
-    let result = (&db.lobbies, &db.players)
-        .transaction(|(lobbies, players)| {
-            let player = players.get(player_id)?;
+    let result = db.transaction(|tx| {
+        let player = tx.player_lobby(player_id)?;
 
-            // in a lobby already?
-            if player.is_some() {
-                // TODO: leaving for UX (requires some abstraction)
-                return Ok(Ok("leave your previous lobby first"));
+        // in a lobby already?
+        if player.is_some() {
+            // TODO: leaving for UX (requires some abstraction)
+            return Ok("leave your previous lobby first");
+        }
+
+        // is there not a lobby?
+        let Some(lobby) = tx.lobby(lobby_id)? else {
+            // TODO: making a lobby for UX (requires some abstraction)
+            return Ok("this channel does not have a lobby, make one instead?");
+        };
+
+        if game::Game::load(tx, &lobby)?.started() {
+            return Ok("the game's already started, wait for the next one.");
+        }
+
+        state::emit(
+            tx,
+            lobby_id,
+            &lobby.game_id,
+            history::Event::Joined {
+                user_id: player_id_val.clone(),
+            },
+        )?;
+
+        Ok("joined the lobby.")
+    })?;
+
+    Ok(InteractionResponse::create(
+        3,
+        Data::content(format!("join lobby: {}", result)),
+    ))
+}
+
+/// What a game command did: the answer for whoever sent it, and what the
+/// rest of the channel should hear about.
+struct Turn {
+    response: InteractionResponse,
+    /// For the channel, when the answer is only for whoever sent it.
+    announcement: Option<(String, Data)>,
+    /// The replay of the game this ended, for the end-of-game message.
+    replay: Option<replay::Replay>,
+}
+
+impl Turn {
+    fn answer(response: InteractionResponse) -> Self {
+        Self {
+            response,
+            announcement: None,
+            replay: None,
+        }
+    }
+}
+
+/// Answers with `turn`'s response, and posts the rest in the background.
+/// Without a bot token there's no posting, so only the answer goes out.
+fn announce(
+    turn: Result<Turn, MagicError>,
+    discord: Option<&discord::Discord>,
+) -> Result<response_types::InteractionResponse, MagicError> {
+    let Turn {
+        response,
+        announcement,
+        replay,
+    } = turn?;
+
+    if let Some(discord) = discord.cloned() {
+        if announcement.is_some() || replay.is_some() {
+            tokio::spawn(async move {
+                if let Some((channel_id, data)) = announcement {
+                    if let Err(e) = discord.send_message(&channel_id, &data).await {
+                        tracing::warn!(channel = %channel_id, error = %e, "was not able to announce a turn");
+                    }
+                }
+                if let Some(replay) = replay {
+                    if let Err(e) = replay.announce(&discord).await {
+                        tracing::warn!(game = %replay.game_id, error = %e, "was not able to announce a game's end");
+                    }
+                }
+            });
+        }
+    }
+
+    Ok(response)
+}
+
+/// Who a command is aimed at, from its required `player` option.
+fn target(
+    interaction: &request_types::Interaction,
+    command: &'static str,
+) -> Result<String, MagicError> {
+    match command_data(interaction, command)?.option("player") {
+        Some(request_types::ApplicationCommandDataValue::String(user_id)) => Ok(user_id.clone()),
+        _ => Err(MagicError::MalformedInteraction(format!(
+            "{} came without a player",
+            command
+        ))),
+    }
+}
+
+/// This channel's lobby and its game, if `player_id` is still playing in
+/// it. Otherwise, why not.
+fn find_game(
+    tx: &dyn database::LobbyTransaction,
+    lobby_id: &str,
+    player_id: &str,
+) -> Result<Result<(Lobby, game::Game), &'static str>, database::TxError> {
+    if tx.player_lobby(player_id)?.as_deref() != Some(lobby_id) {
+        return Ok(Err("you aren't in this channel's lobby."));
+    }
+
+    let Some(lobby) = tx.lobby(lobby_id)? else {
+        return Ok(Err("this channel does not have a lobby."));
+    };
+    let game = game::Game::load(tx, &lobby)?;
+
+    if !game.started() {
+        Ok(Err("the game hasn't started yet."))
+    } else if !game.in_play(player_id) {
+        Ok(Err("you've been banished, so you can only watch."))
+    } else {
+        Ok(Ok((lobby, game)))
+    }
+}
+
+/// Tells the channel what a turn set off.
+fn describe(happened: &[history::Event], game: &game::Game) -> String {
+    let mut lines = vec![];
+
+    for event in happened {
+        match event {
+            history::Event::PhaseChanged {
+                phase: game::Phase::Day,
+            } => {
+                lines.push("day breaks!".to_string());
+                if let Some(experimented) = &game.experimented {
+                    lines.push(format!(
+                        "<@{}> was experimented on in the night, so they sit out today's vote.",
+                        experimented
+                    ));
+                }
+                lines.push(
+                    "talk it over, then /vote for who you think the magician is.".to_string(),
+                );
             }
+            history::Event::PhaseChanged {
+                phase: game::Phase::Night,
+            } => lines.push("night falls.".to_string()),
+            history::Event::Banished { user_id } => {
+                lines.push(format!("<@{}> is banished.", user_id));
+            }
+            _ => {}
+        }
+    }
 
-            let lobby_str = lobbies.get(lobby_id)?;
+    if let Some(winner) = game.winner() {
+        lines.push(format!("that's the game: the {} wins!", winner));
+    }
 
-            // is there not a lobby?
-            if lobby_str.is_none() {
-                // TODO: making a lobby for UX (requires some abstraction)
-                return Ok(Ok("this channel does not have a lobby, make one instead?"));
+    lines.join("\n")
+}
+
+/// Finishes the game if it's over, returning its replay.
+fn conclude(
+    db: &Database,
+    lobby_id: &str,
+    lobby: &Lobby,
+    game: &game::Game,
+) -> Result<Option<replay::Replay>, MagicError> {
+    match game.winner() {
+        Some(winner) => game::finish(db, &game.result(lobby, lobby_id, winner)),
+        None => Ok(None),
+    }
+}
+
+/// A turn only its taker sees the answer to, so what it set off is posted
+/// on its own.
+fn secret_turn(
+    db: &Database,
+    lobby_id: &str,
+    lobby: &Lobby,
+    game: &game::Game,
+    happened: &[history::Event],
+    response: InteractionResponse,
+) -> Result<Turn, MagicError> {
+    Ok(Turn {
+        response,
+        announcement: (!happened.is_empty()).then(|| {
+            (
+                lobby_id.to_string(),
+                Data::content(describe(happened, game)),
+            )
+        }),
+        replay: conclude(db, lobby_id, lobby, game)?,
+    })
+}
+
+fn start_game(
+    interaction: request_types::Interaction,
+    db: Database,
+) -> Result<response_types::InteractionResponse, MagicError> {
+    use ring::rand::SecureRandom;
+
+    let player_id = interaction.clone().member().user().id();
+    let lobby_id_val = interaction.channel_id();
+    let lobby_id = lobby_id_val.as_str();
+
+    // outside the transaction, so a retry hands out the same roles
+    let mut random = [0; 8];
+    ring::rand::SystemRandom::new()
+        .fill(&mut random)
+        .map_err(|_| {
+            tracing::error!("no randomness to hand out roles with");
+            MagicError::GenericError
+        })?;
+    let magician = u32::from_le_bytes([random[0], random[1], random[2], random[3]]) as usize;
+    let oracle = u32::from_le_bytes([random[4], random[5], random[6], random[7]]) as usize;
+
+    let result = db.transaction(|tx| {
+        if tx.player_lobby(&player_id)?.as_deref() != Some(lobby_id) {
+            return Ok("you need to be in this channel's lobby to start it.".to_string());
+        }
+
+        let Some(lobby) = tx.lobby(lobby_id)? else {
+            return Ok("this channel does not have a lobby, make one instead?".to_string());
+        };
+
+        if lobby.creator != player_id {
+            return Ok("only whoever made the lobby can start it.".to_string());
+        }
+        // there's nowhere to keep the game, so make a new lobby
+        if lobby.game_id.is_empty() {
+            return Ok("this lobby is too old to play in, make a new one.".to_string());
+        }
+        if game::Game::load(tx, &lobby)?.started() {
+            return Ok("the game's already started.".to_string());
+        }
+        if lobby.players.len() < game::MIN_PLAYERS {
+            return Ok(format!(
+                "you need at least {} players to start.",
+                game::MIN_PLAYERS
+            ));
+        }
+
+        let roles = game::assign_roles(&lobby.players, magician, oracle);
+        state::emit(
+            tx,
+            lobby_id,
+            &lobby.game_id,
+            history::Event::RolesAssigned { roles },
+        )?;
+        state::emit(
+            tx,
+            lobby_id,
+            &lobby.game_id,
+            history::Event::PhaseChanged {
+                phase: game::Phase::Night,
+            },
+        )?;
+
+        Ok(format!(
+            "the game's begun with {} players! use /role to see what you are. night falls.",
+            lobby.players.len()
+        ))
+    })?;
+
+    Ok(InteractionResponse::create(
+        3,
+        Data::content(format!("start: {}", result)),
+    ))
+}
+
+fn show_role(
+    interaction: request_types::Interaction,
+    db: Database,
+) -> Result<response_types::InteractionResponse, MagicError> {
+    let player_id = interaction.clone().member().user().id();
+    let lobby_id_val = interaction.channel_id();
+    let lobby_id = lobby_id_val.as_str();
+
+    let result = db.transaction(|tx| {
+        let (_, game) = match find_game(tx, lobby_id, &player_id)? {
+            Ok(found) => found,
+            Err(why) => return Ok(why.to_string()),
+        };
+
+        Ok(match game.role(&player_id) {
+            Some(game::Role::Magician) => "you're the magician! experiment on someone each night with /experiment, and don't get banished.".to_string(),
+            Some(game::Role::Oracle) => "you're the oracle! each night, /read someone to find out if they're the magician.".to_string(),
+            Some(game::Role::Villager) => "you're a villager! find the magician and vote to banish them.".to_string(),
+            None => "you don't have a role in this game.".to_string(),
+        })
+    })?;
+
+    Ok(InteractionResponse::create(
+        4,
+        Data::ephemeral_content(format!("role: {}", result)),
+    ))
+}
+
+/// The magician's move, made at night. Only they see the answer, so nobody
+/// learns who they are from it.
+fn kill_player(interaction: request_types::Interaction, db: Database) -> Result<Turn, MagicError> {
+    let player_id = interaction.clone().member().user().id();
+    let target = target(&interaction, "experiment")?;
+    let lobby_id_val = interaction.channel_id();
+    let lobby_id = lobby_id_val.as_str();
+
+    let turn = db.transaction(|tx| {
+        let (lobby, mut game) = match find_game(tx, lobby_id, &player_id)? {
+            Ok(found) => found,
+            Err(why) => return Ok(Err(why)),
+        };
+
+        if game.role(&player_id) != Some(game::Role::Magician) {
+            return Ok(Err("only the magician can experiment."));
+        }
+        if game.phase != Some(game::Phase::Night) {
+            return Ok(Err("you can only experiment at night."));
+        }
+        if game.experimented.is_some() {
+            return Ok(Err("you've already experimented tonight."));
+        }
+        if target == player_id {
+            return Ok(Err("you can't experiment on yourself."));
+        }
+        if !game.in_play(&target) {
+            return Ok(Err("they aren't playing."));
+        }
+
+        let happened = game::play(
+            tx,
+            lobby_id,
+            &lobby,
+            &mut game,
+            history::Event::Experimented {
+                magician: player_id.clone(),
+                target: target.clone(),
+            },
+        )?;
+
+        Ok(Ok((lobby, game, happened)))
+    })?;
+
+    let response = |result: &str| {
+        InteractionResponse::create(
+            4,
+            Data::ephemeral_content(format!("experiment: {}", result)),
+        )
+    };
+    match turn {
+        Ok((lobby, game, happened)) => secret_turn(
+            &db,
+            lobby_id,
+            &lobby,
+            &game,
+            &happened,
+            response(&format!(
+                "you experiment on <@{}>. they'll sit out tomorrow's vote.",
+                target
+            )),
+        ),
+        Err(why) => Ok(Turn::answer(response(why))),
+    }
+}
+
+/// The oracle's move, made at night. Only they see the answer.
+fn read_player(interaction: request_types::Interaction, db: Database) -> Result<Turn, MagicError> {
+    let player_id = interaction.clone().member().user().id();
+    let target = target(&interaction, "read")?;
+    let lobby_id_val = interaction.channel_id();
+    let lobby_id = lobby_id_val.as_str();
+
+    let turn = db.transaction(|tx| {
+        let (lobby, mut game) = match find_game(tx, lobby_id, &player_id)? {
+            Ok(found) => found,
+            Err(why) => return Ok(Err(why)),
+        };
+
+        if game.role(&player_id) != Some(game::Role::Oracle) {
+            return Ok(Err("only the oracle can read people."));
+        }
+        if game.phase != Some(game::Phase::Night) {
+            return Ok(Err("you can only read people at night."));
+        }
+        if game.read.is_some() {
+            return Ok(Err("you've already read someone tonight."));
+        }
+        if target == player_id {
+            return Ok(Err("you already know what you are."));
+        }
+        if !game.in_play(&target) {
+            return Ok(Err("they aren't playing."));
+        }
+
+        let happened = game::play(
+            tx,
+            lobby_id,
+            &lobby,
+            &mut game,
+            history::Event::Read {
+                oracle: player_id.clone(),
+                target: target.clone(),
+            },
+        )?;
+
+        Ok(Ok((lobby, game, happened)))
+    })?;
+
+    let response = |result: &str| {
+        InteractionResponse::create(4, Data::ephemeral_content(format!("read: {}", result)))
+    };
+    match turn {
+        Ok((lobby, game, happened)) => {
+            let answer = if game.role(&target) == Some(game::Role::Magician) {
+                format!("<@{}> is the magician!", target)
+            } else {
+                format!("<@{}> isn't the magician.", target)
             };
+            secret_turn(&db, lobby_id, &lobby, &game, &happened, response(&answer))
+        }
+        Err(why) => Ok(Turn::answer(response(why))),
+    }
+}
 
-            let mut lobby = decode_lobby(&lobby_str.unwrap());
+/// Everyone's move, made during the day. Votes are out in the open, so the
+/// whole channel sees the answer, and what the vote set off with it.
+fn vote_player(interaction: request_types::Interaction, db: Database) -> Result<Turn, MagicError> {
+    let player_id = interaction.clone().member().user().id();
+    let target = target(&interaction, "vote")?;
+    let lobby_id_val = interaction.channel_id();
+    let lobby_id = lobby_id_val.as_str();
 
-            lobby.players.push(player_id_val.clone());
+    let turn = db.transaction(|tx| {
+        let (lobby, mut game) = match find_game(tx, lobby_id, &player_id)? {
+            Ok(found) => found,
+            Err(why) => return Ok(Err(why)),
+        };
 
-            lobbies.insert(
+        if game.phase != Some(game::Phase::Day) {
+            return Ok(Err("you can only vote during the day."));
+        }
+        if !game.can_vote(&player_id) {
+            return Ok(Err(
+                "you were experimented on last night, so you sit this vote out.",
+            ));
+        }
+        if target == player_id {
+            return Ok(Err("you can't vote for yourself."));
+        }
+        if !game.in_play(&target) {
+            return Ok(Err("they aren't playing."));
+        }
+
+        let happened = game::play(
+            tx,
+            lobby_id,
+            &lobby,
+            &mut game,
+            history::Event::Voted {
+                voter: player_id.clone(),
+                target: target.clone(),
+            },
+        )?;
+
+        Ok(Ok((lobby, game, happened)))
+    })?;
+
+    match turn {
+        Ok((lobby, game, happened)) => {
+            let mut result = format!("vote: <@{}> votes for <@{}>.", player_id, target);
+            if !happened.is_empty() {
+                result.push('\n');
+                result.push_str(&describe(&happened, &game));
+            }
+
+            Ok(Turn {
+                response: InteractionResponse::create(3, Data::content(result)),
+                announcement: None,
+                replay: conclude(&db, lobby_id, &lobby, &game)?,
+            })
+        }
+        Err(why) => Ok(Turn::answer(InteractionResponse::create(
+            4,
+            Data::ephemeral_content(format!("vote: {}", why)),
+        ))),
+    }
+}
+
+fn leave_lobby(
+    interaction: request_types::Interaction,
+    db: Database,
+) -> Result<response_types::InteractionResponse, MagicError> {
+    let player_id = interaction.clone().member().user().id();
+    let lobby_id_val = interaction.channel_id();
+    let lobby_id = lobby_id_val.as_str();
+
+    let result = db.transaction(|tx| {
+        let player = tx.player_lobby(&player_id)?;
+
+        if player.as_deref() != Some(lobby_id) {
+            return Ok("you need to be in a lobby to leave it.");
+        };
+
+        let Some(lobby) = tx.lobby(lobby_id)? else {
+            tx.remove_player(&player_id)?;
+            return Ok("there was no such lobby???");
+        };
+
+        if lobby.creator.as_str() == player_id {
+            // you're the creator, so everyone goes. that's also how a game
+            // nobody's finishing gets ended.
+            state::emit(tx, lobby_id, &lobby.game_id, history::Event::Disbanded)?;
+
+            Ok("disbanded the lobby!")
+        } else if game::Game::load(tx, &lobby)?.started() {
+            Ok("you can't leave in the middle of a game.")
+        } else {
+            // you're not the creator
+            state::emit(
+                tx,
                 lobby_id,
-                encode_lobby(&Lobby {
-                    creator: lobby.creator,
-                    players: lobby.players,
-                }),
+                &lobby.game_id,
+                history::Event::Left {
+                    user_id: player_id.clone(),
+                },
             )?;
 
-            players.insert(player_id, lobby_id)?;
+            Ok("left the lobby!")
+        }
+    })?;
 
-            ConflictableTransactionResult::<sled::Result<&'static str>, Infallible>::Ok(Ok(
-                "joined the lobby.",
-            ))
+    Ok(InteractionResponse::create(
+        3,
+        Data::content(format!("leave lobby: {}", result)),
+    ))
+}
+
+fn guild_settings(
+    interaction: request_types::Interaction,
+    db: Database,
+) -> Result<response_types::InteractionResponse, MagicError> {
+    let data = command_data(&interaction, "settings")?;
+    let guild_id = interaction.clone().guild_id();
+    let lobby_timeout = data.option("lobby-timeout");
+
+    // changing settings requires MANAGE_GUILD
+    if lobby_timeout.is_some() && !has_permission(&interaction, 5)? {
+        return Ok(InteractionResponse::create(
+            4,
+            Data::ephemeral_content(
+                "settings: you need the Manage Server permission to change these".to_string(),
+            ),
+        ));
+    }
+
+    let mut guild_settings = settings::get(&db, &guild_id)?;
+
+    if let Some(request_types::ApplicationCommandDataValue::Number(minutes)) = lobby_timeout {
+        if *minutes < 1 {
+            return Ok(InteractionResponse::create(
+                4,
+                Data::ephemeral_content(
+                    "settings: lobbies have to be allowed at least a minute".to_string(),
+                ),
+            ));
+        }
+
+        guild_settings.lobby_timeout = u64::try_from(*minutes)
+            .unwrap_or(u64::MAX)
+            .saturating_mul(60);
+        settings::set(&db, &guild_id, &guild_settings)?;
+    }
+
+    Ok(InteractionResponse::create(
+        4,
+        Data::ephemeral_content(format!(
+            "settings: lobbies close after {} minutes without anyone joining or leaving.",
+            guild_settings.lobby_timeout / 60
+        )),
+    ))
+}
+
+fn show_history(
+    interaction: request_types::Interaction,
+    db: Database,
+) -> Result<response_types::InteractionResponse, MagicError> {
+    let channel_id = interaction.channel_id();
+    let games = history::recent_games(&db, &channel_id)?;
+
+    if games.is_empty() {
+        return Ok(InteractionResponse::create(
+            3,
+            Data::content("history: no games here yet.".to_string()),
+        ));
+    }
+
+    let lines = games
+        .iter()
+        .map(|(game_id, summary)| {
+            format!(
+                "`{}`: started by <@{}>, {} players, {}",
+                game_id,
+                summary.creator,
+                summary.players.len(),
+                summary.describe_ending()
+            )
         })
-        .expect("tx error")?;
+        .collect::<Vec<_>>()
+        .join("\n");
 
     Ok(InteractionResponse::create(
         3,
-        Data::content(format!("join lobby: {}", result)),
+        Data::content(format!("recent games here:\n{}", lines)),
     ))
 }
 
-fn kill_player(
-    _interaction: request_types::Interaction,
-    _db: Database,
+/// Discord won't send messages longer than this, in characters.
+const MAX_CONTENT: usize = 2000;
+/// Enough for `show_game` to say how many events it left out.
+const LEFT_OUT_ROOM: usize = 80;
+
+fn show_game(
+    interaction: request_types::Interaction,
+    db: Database,
+) -> Result<response_types::InteractionResponse, MagicError> {
+    let data = command_data(&interaction, "game")?;
+
+    let game_id = match data.option("id") {
+        Some(request_types::ApplicationCommandDataValue::String(game_id)) => game_id.clone(),
+        _ => {
+            return Err(MagicError::MalformedInteraction(
+                "game needs an id".to_string(),
+            ))
+        }
+    };
+
+    // games from other servers aren't anyone here's business
+    let guild_id = interaction.guild_id();
+    let summary = match history::summary(&db, &game_id)? {
+        Some(summary) if summary.guild_id == guild_id => summary,
+        _ => {
+            return Ok(InteractionResponse::create(
+                3,
+                Data::content(format!("game: there's no game `{}`.", game_id)),
+            ))
+        }
+    };
+
+    let header = format!(
+        "game `{}` in <#{}>, {}:",
+        game_id,
+        summary.channel_id,
+        summary.describe_ending()
+    );
+    let events = history::events(&db, &game_id)?;
+    let lines = events
+        .iter()
+        .map(|logged| format!("<t:{}:T> {}", logged.at, logged.event))
+        .collect::<Vec<_>>();
+
+    // the newest events are the interesting ones, so the oldest go first.
+    // `left_out` is how many of them didn't fit.
+    let mut left_out = lines.len();
+    let mut length = header.chars().count();
+    for line in lines.iter().rev() {
+        let needed = line.chars().count() + 1;
+        // unless this is the last one, leave room to say some were left out
+        let room = if left_out == 1 { 0 } else { LEFT_OUT_ROOM };
+        if length + needed + room > MAX_CONTENT {
+            break;
+        }
+        length += needed;
+        left_out -= 1;
+    }
+
+    let mut content = header;
+    if left_out > 0 {
+        content.push_str(&format!(
+            "\n({} earlier events left out, the game's replay has all of them)",
+            left_out
+        ));
+    }
+    for line in &lines[left_out..] {
+        content.push('\n');
+        content.push_str(line);
+    }
+
+    Ok(InteractionResponse::create(3, Data::content(content)))
+}
+
+fn show_stats(
+    interaction: request_types::Interaction,
+    db: Database,
 ) -> Result<response_types::InteractionResponse, MagicError> {
+    let data = command_data(&interaction, "stats")?;
+
+    let user_id = match data.option("player") {
+        Some(request_types::ApplicationCommandDataValue::String(user_id)) => user_id.clone(),
+        _ => interaction.member().user().id(),
+    };
+    let player_stats = stats::get(&db, &user_id)?;
+    let ratings = rating::get(&db, &user_id)?;
+
+    if player_stats.games_played == 0 {
+        return Ok(InteractionResponse::create(
+            3,
+            Data::content(format!("stats: <@{}> hasn't finished a game yet.", user_id)),
+        ));
+    }
+
+    let wins = game::Role::ALL
+        .iter()
+        .map(|role| {
+            format!(
+                "{} as {}",
+                player_stats.wins.get(role).copied().unwrap_or(0),
+                role
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let ratings = game::Faction::ALL
+        .iter()
+        .map(|faction| format!("{:.0} as {}", ratings.get(*faction), faction))
+        .collect::<Vec<_>>()
+        .join(", ");
+
     Ok(InteractionResponse::create(
         3,
-        Data::content("kill player".to_string()),
+        Data::content(format!(
+            "stats for <@{}>: {} games, {} wins ({}). banished {} times, experimented on {} times, {} correct oracle reads. rated {}.",
+            user_id,
+            player_stats.games_played,
+            player_stats.total_wins(),
+            wins,
+            player_stats.times_banished,
+            player_stats.times_experimented_on,
+            player_stats.correct_reads,
+            ratings
+        )),
     ))
 }
 
-fn vote_player(
-    _interaction: request_types::Interaction,
-    _db: Database,
+fn show_profile(
+    interaction: request_types::Interaction,
+    db: Database,
 ) -> Result<response_types::InteractionResponse, MagicError> {
+    let data = command_data(&interaction, "profile")?;
+
+    let user_id = match data.option("player") {
+        Some(request_types::ApplicationCommandDataValue::String(user_id)) => user_id.clone(),
+        _ => interaction.member().user().id(),
+    };
+    let player_stats = stats::get(&db, &user_id)?;
+    let unlocked = achievements::get(&db, &user_id)?;
+
+    let earned = achievements::ALL
+        .iter()
+        .filter(|achievement| unlocked.achievements.contains_key(achievement.id))
+        .map(|achievement| format!("**{}**: {}", achievement.name, achievement.description))
+        .collect::<Vec<_>>();
+
+    let achievement_lines = if earned.is_empty() {
+        "no achievements yet.".to_string()
+    } else {
+        format!(
+            "achievements ({} of {}):\n{}",
+            earned.len(),
+            achievements::ALL.len(),
+            earned.join("\n")
+        )
+    };
+
     Ok(InteractionResponse::create(
         3,
-        Data::content("vote player".to_string()),
+        Data::content(format!(
+            "profile for <@{}>: {} games, {} wins.\n{}",
+            user_id,
+            player_stats.games_played,
+            player_stats.total_wins(),
+            achievement_lines
+        )),
     ))
 }
 
-fn leave_lobby(
+fn show_leaderboard(
     interaction: request_types::Interaction,
     db: Database,
 ) -> Result<response_types::InteractionResponse, MagicError> {
-    let player_id = interaction.clone().member().user().id();
-    let lobby_id_val = interaction.channel_id();
-    let lobby_id = lobby_id_val.as_str();
+    let data = command_data(&interaction, "leaderboard")?;
+    let guild_id = interaction.guild_id();
 
-    let result = (&db.players, &db.lobbies)
-        .transaction(|(players, lobbies)| {
-            let player = players.get(&player_id)?;
+    let category = match data.option("category") {
+        Some(request_types::ApplicationCommandDataValue::String(name)) => {
+            leaderboard::Category::parse(name)
+        }
+        _ => Some(leaderboard::Category::Wins),
+    };
+    let category = match category {
+        Some(category) => category,
+        None => {
+            return Ok(InteractionResponse::create(
+                4,
+                Data::ephemeral_content("leaderboard: no such category".to_string()),
+            ))
+        }
+    };
 
-            if player.is_none()
-                || std::str::from_utf8(player.unwrap().as_ref()).unwrap() != lobby_id
-            {
-                return Ok(Ok("you need to be in a lobby to leave it."));
-            };
+    let current = leaderboard::current_season(&db, &guild_id)?.number;
+    let season = match data.option("season") {
+        Some(request_types::ApplicationCommandDataValue::Number(season))
+            if *season >= 1 && *season <= i64::from(current) =>
+        {
+            *season as u32
+        }
+        Some(_) => {
+            return Ok(InteractionResponse::create(
+                4,
+                Data::ephemeral_content(format!("leaderboard: seasons go from 1 to {}", current)),
+            ))
+        }
+        None => current,
+    };
 
-            players.remove(player_id.as_str())?;
+    let top = leaderboard::top(&db, &guild_id, season, category)?;
 
-            let lobby_str = lobbies.get(lobby_id)?;
+    let mut content = format!("leaderboard: {}, season {}", category.describe(), season);
+    if top.is_empty() {
+        content.push_str("\nnobody's on it yet!");
+    }
+    for (place, (user_id, standing)) in top.iter().enumerate() {
+        let line = match category {
+            leaderboard::Category::Wins => format!(
+                "{}. <@{}>: {} wins in {} games",
+                place + 1,
+                user_id,
+                standing.wins,
+                standing.games
+            ),
+            leaderboard::Category::WinRate => format!(
+                "{}. <@{}>: won {}% of {} games",
+                place + 1,
+                user_id,
+                standing.wins * 100 / standing.games,
+                standing.games
+            ),
+            leaderboard::Category::Magician => format!(
+                "{}. <@{}>: {} wins in {} games as magician",
+                place + 1,
+                user_id,
+                standing.magician_wins,
+                standing.magician_games
+            ),
+        };
+        content.push('\n');
+        content.push_str(&line);
+    }
 
-            if lobby_str.is_none() {
-                return Ok(Ok("there was no such lobby???"));
-            };
+    Ok(InteractionResponse::create(3, Data::content(content)))
+}
 
-            let mut lobby = decode_lobby(&lobby_str.unwrap());
+fn season(
+    interaction: request_types::Interaction,
+    db: Database,
+) -> Result<response_types::InteractionResponse, MagicError> {
+    let data = command_data(&interaction, "season")?;
+    let guild_id = interaction.clone().guild_id();
 
-            if lobby.creator.as_str() == player_id {
-                // you're the creator
-                for player in lobby.players {
-                    if player != player_id {
-                        players.remove(player.as_str())?;
-                    };
-                }
+    let starting = matches!(
+        data.option("start-new"),
+        Some(request_types::ApplicationCommandDataValue::Boolean(true))
+    );
 
-                lobbies.remove(lobby_id)?;
+    if !starting {
+        let current = leaderboard::current_season(&db, &guild_id)?;
+        return Ok(InteractionResponse::create(
+            3,
+            Data::content(format!("season: this is season {}.", current.number)),
+        ));
+    }
 
-                Ok(Ok("disbanded the lobby!"))
-            } else {
-                // you're not the creator
-                lobby.players.retain(|n| n == &player_id);
-                lobbies.insert(
-                    lobby_id,
-                    encode_lobby(&Lobby {
-                        creator: lobby.creator,
-                        players: lobby.players,
-                    }),
-                )?;
-
-                ConflictableTransactionResult::<sled::Result<&'static str>, Infallible>::Ok(Ok(
-                    "left the lobby!",
-                ))
-            }
-        })
-        .expect("tx error")?;
+    // starting a season requires MANAGE_GUILD
+    if !has_permission(&interaction, 5)? {
+        return Ok(InteractionResponse::create(
+            4,
+            Data::ephemeral_content(
+                "season: you need the Manage Server permission to start a new season".to_string(),
+            ),
+        ));
+    }
+
+    let started = leaderboard::start_season(&db, &guild_id)?;
 
     Ok(InteractionResponse::create(
         3,
-        Data::content(format!("leave lobby: {}", result)),
+        Data::content(format!(
+            "season: season {} has begun! the old rankings are still around with /leaderboard season:{}.",
+            started.number,
+            started.number - 1
+        )),
     ))
 }
 
 pub async fn handle_interaction(
     interaction: request_types::Interaction,
     db: Database,
+    commands: &config::Commands,
+    limits: &ratelimit::RateLimits,
+    discord: Option<&discord::Discord>,
 ) -> Result<response_types::InteractionResponse, MagicError> {
-    let data = interaction.clone().data().ok_or(MagicError::GenericError)?;
-
-    match data.id().as_str() {
-        "796995810038382642" => create_lobby(interaction, db),
-        "796996870815744010" => join_lobby(interaction, db),
-        "796999207046742027" => kill_player(interaction, db),
-        "796999927782834176" => vote_player(interaction, db),
-        "801198519263559690" => leave_lobby(interaction, db),
-        _ => Ok(InteractionResponse::create(
+    let data = interaction
+        .clone()
+        .data()
+        .ok_or_else(|| MagicError::MalformedInteraction("missing data".to_string()))?;
+
+    // before anything gets near the database
+    let user_id = interaction.clone().member().user().id();
+    let guild_id = interaction.clone().guild_id();
+    if let Err(limited) = limits.take(&user_id, &guild_id, std::time::Instant::now()) {
+        tracing::debug!(?limited, "rate limited");
+        let who = match limited {
+            ratelimit::Limited::User => "you're",
+            ratelimit::Limited::Guild => "this server is",
+        };
+        return Ok(InteractionResponse::create(
             4,
-            Data::content("Command not set up.".to_string()),
-        )),
+            Data::ephemeral_content(format!(
+                "slow down! {} sending commands too quickly, try again in a bit.",
+                who
+            )),
+        ));
+    }
+
+    match commands.command(&data.clone().id()) {
+        Some(Command::CreateLobby) => create_lobby(interaction, db),
+        Some(Command::JoinLobby) => join_lobby(interaction, db),
+        Some(Command::KillPlayer) => announce(kill_player(interaction, db), discord),
+        Some(Command::VotePlayer) => announce(vote_player(interaction, db), discord),
+        Some(Command::LeaveLobby) => leave_lobby(interaction, db),
+        // these haven't been registered yet, so there's no id to go by
+        None => match data.name() {
+            "start" => start_game(interaction, db),
+            "role" => show_role(interaction, db),
+            "read" => announce(read_player(interaction, db), discord),
+            "settings" => guild_settings(interaction, db),
+            "stats" => show_stats(interaction, db),
+            "profile" => show_profile(interaction, db),
+            "leaderboard" => show_leaderboard(interaction, db),
+            "season" => season(interaction, db),
+            "history" => show_history(interaction, db),
+            "game" => show_game(interaction, db),
+            _ => Ok(InteractionResponse::create(
+                4,
+                Data::content("Command not set up.".to_string()),
+            )),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use history::Event;
+
+    fn game_command(guild_id: &str, game_id: &str) -> request_types::Interaction {
+        command(
+            guild_id,
+            "someone",
+            "game",
+            serde_json::json!([{ "name": "id", "value": game_id }]),
+        )
+    }
+
+    fn command(
+        guild_id: &str,
+        user_id: &str,
+        name: &str,
+        options: serde_json::Value,
+    ) -> request_types::Interaction {
+        serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "type": 2,
+            "data": {
+                "id": "2",
+                "name": name,
+                "options": options,
+            },
+            "guild_id": guild_id,
+            "channel_id": "channel",
+            "member": {
+                "user": {
+                    "id": user_id,
+                    "username": user_id,
+                    "discriminator": "0",
+                    "public_flags": 0,
+                },
+                "roles": [],
+                "deaf": false,
+                "mute": false,
+                "permissions": "0",
+            },
+            "token": "token",
+            "version": 1,
+        }))
+        .unwrap()
+    }
+
+    fn content(response: &InteractionResponse) -> String {
+        let response = serde_json::to_value(response).unwrap();
+        response["data"]["content"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn long_games_are_cut_short_and_other_servers_are_hidden() {
+        let db = Database::make(sled::Config::new().temporary(true).open().unwrap());
+        let emit = |event: Event| {
+            db.transaction(|tx| state::emit(tx, "channel", "7", event.clone()))
+                .unwrap();
+        };
+
+        emit(Event::Created {
+            guild_id: "guild".to_string(),
+            channel_id: "channel".to_string(),
+            creator: "creator".to_string(),
+        });
+        for i in 0..200 {
+            emit(Event::Joined {
+                user_id: format!("player {}", i),
+            });
+        }
+
+        let shown = content(&show_game(game_command("guild", "7"), db.clone()).unwrap());
+        assert!(shown.chars().count() <= MAX_CONTENT);
+        assert!(shown.contains("earlier events left out"));
+        assert!(shown.ends_with("<@player 199> joined"));
+
+        let elsewhere = content(&show_game(game_command("elsewhere", "7"), db).unwrap());
+        assert_eq!(elsewhere, "game: there's no game `7`.");
+    }
+
+    #[test]
+    fn a_game_is_played_to_the_end() {
+        let db = Database::make(sled::Config::new().temporary(true).open().unwrap());
+        let run = |user_id: &str, name: &str, target: Option<&str>| {
+            let options = match target {
+                Some(target) => serde_json::json!([{ "name": "player", "value": target }]),
+                None => serde_json::json!([]),
+            };
+            command("guild", user_id, name, options)
+        };
+
+        create_lobby(run("ann", "create", None), db.clone()).unwrap();
+        let early = start_game(run("ann", "start", None), db.clone()).unwrap();
+        assert!(content(&early).contains("at least 3 players"));
+        for player in &["bo", "cy", "di"] {
+            join_lobby(run(player, "join", None), db.clone()).unwrap();
+        }
+        start_game(run("ann", "start", None), db.clone()).unwrap();
+
+        let late = join_lobby(run("ed", "join", None), db.clone()).unwrap();
+        assert!(content(&late).contains("already started"));
+
+        let game = game::Game::from_events(&history::events(&db, "1").unwrap());
+        let with = |role: game::Role| {
+            game.roles
+                .iter()
+                .filter(|(_, other)| **other == role)
+                .map(|(user_id, _)| user_id.as_str())
+                .collect::<Vec<_>>()
+        };
+        let magician = with(game::Role::Magician)[0];
+        let oracle = with(game::Role::Oracle)[0];
+        let villagers = with(game::Role::Villager);
+        assert_eq!(villagers.len(), 2);
+
+        let role = show_role(run(oracle, "role", None), db.clone()).unwrap();
+        assert!(content(&role).contains("you're the oracle"));
+        let wrong = kill_player(run(oracle, "experiment", Some(magician)), db.clone()).unwrap();
+        assert!(content(&wrong.response).contains("only the magician"));
+
+        // nothing happens for everyone to see until both of them are done
+        let experiment =
+            kill_player(run(magician, "experiment", Some(villagers[0])), db.clone()).unwrap();
+        assert!(experiment.announcement.is_none());
+        let read = read_player(run(oracle, "read", Some(magician)), db.clone()).unwrap();
+        assert!(content(&read.response).contains("is the magician"));
+        let (_, daybreak) = read.announcement.unwrap();
+        let daybreak = serde_json::to_value(&daybreak).unwrap();
+        assert!(daybreak["content"].as_str().unwrap().contains("day breaks"));
+
+        let sitting_out =
+            vote_player(run(villagers[0], "vote", Some(magician)), db.clone()).unwrap();
+        assert!(content(&sitting_out.response).contains("sit this vote out"));
+
+        let first = vote_player(run(oracle, "vote", Some(magician)), db.clone()).unwrap();
+        assert!(first.replay.is_none());
+        vote_player(run(magician, "vote", Some(oracle)), db.clone()).unwrap();
+        let last = vote_player(run(villagers[1], "vote", Some(magician)), db.clone()).unwrap();
+        let shown = content(&last.response);
+        assert!(shown.contains(&format!("<@{}> is banished", magician)));
+        assert!(shown.contains("the village wins"));
+        assert!(last.replay.is_some());
+
+        for player in &["ann", "bo", "cy", "di"] {
+            assert_eq!(stats::get(&db, player).unwrap().games_played, 1);
+        }
+        assert_eq!(stats::get(&db, oracle).unwrap().correct_reads, 1);
+        assert_eq!(
+            stats::get(&db, villagers[0]).unwrap().times_experimented_on,
+            1
+        );
+        // and the lobby's gone, so everyone's free to play again
+        assert!(db.lobbies().unwrap().is_empty());
+        assert!(db.players().unwrap().is_empty());
     }
 }
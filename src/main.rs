@@ -3,155 +3,1266 @@
 // editors hate this one trick:
 // #![warn(clippy::cargo)]
 #![allow(clippy::multiple_crate_versions)]
+// the rest of the crate doesn't do this either
+#![allow(clippy::uninlined_format_args)]
 
-#[macro_use]
-extern crate dotenv_codegen;
-
+use futures::FutureExt;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server};
 use hyper::{Method, StatusCode};
-use ring::signature;
 use std::convert::{Infallible, TryFrom, TryInto};
-use std::net::SocketAddr;
+use std::future::Future;
+use std::io::{IsTerminal, Write};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Semaphore};
+use tracing::Instrument;
 
+use magic::config::{App, Config, LogFormat, LogLevel};
+use magic::discord::Discord;
+use magic::metrics::Metrics;
+use magic::ratelimit::RateLimits;
+use magic::response_types::{Data, InteractionResponse};
+use magic::seen::SeenInteractions;
+use magic::snapshot::Snapshots;
 use magic::Database;
 
+/// Ctrl+C, or SIGTERM from systemd, docker and the like.
 async fn shutdown_signal() {
-    tokio::signal::ctrl_c()
-        .await
-        .expect("failed to do ctrl+c handling")
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!(error = %e, "was not able to listen for ctrl+c");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "was not able to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
 }
 
-const DISCORD_PUBLIC_KEY_STRING: &str = dotenv!("PUBLIC_KEY");
+/// Done once `stop` turns true.
+async fn stopped(mut stop: watch::Receiver<bool>) {
+    // it's only dropped after being set, so an error means the same
+    let _ = stop.wait_for(|stop| *stop).await;
+}
+
+/// What every request gets to use.
+struct Shared {
+    config: Config,
+    /// Each app's database, in the same order as `config.apps`.
+    databases: Vec<Database>,
+    /// Each app's bot, if it has a token, in the same order as `config.apps`.
+    discords: Vec<Option<Discord>>,
+    seen: SeenInteractions,
+    metrics: Metrics,
+    /// One for each request being worked on, up to `max_concurrent_requests`.
+    requests: Semaphore,
+    limits: RateLimits,
+}
+
+impl Shared {
+    fn apps(&self) -> impl Iterator<Item = (&App, &Database)> {
+        self.config.apps.iter().zip(&self.databases)
+    }
+
+    /// The bot that posts for `app`, if it has one.
+    fn discord(&self, app: &App) -> Option<&Discord> {
+        self.config
+            .apps
+            .iter()
+            .zip(&self.discords)
+            .find(|(other, _)| other.name == app.name)
+            .and_then(|(_, discord)| discord.as_ref())
+    }
+
+    /// Whichever app signed this is the one it's for. `only` limits which
+    /// ones are tried.
+    fn signed_by(
+        &self,
+        only: Option<&str>,
+        timestamp: &[u8],
+        body: &[u8],
+        signature: &[u8],
+    ) -> Option<(&App, &Database)> {
+        self.apps()
+            .filter(|(app, _)| only.is_none_or(|name| app.name == name))
+            .find(|(app, _)| app.verifier.verify(timestamp, body, signature))
+    }
+}
+
+// `/interactions/<app>` only takes that app's requests, while `/` takes any
+const INTERACTIONS_PATH: &str = "/interactions/";
 
 async fn handle_request(
     req: Request<Body>,
-    db: Database,
+    shared: Arc<Shared>,
 ) -> Result<Response<Body>, magic::MagicError> {
-    let public_key = signature::UnparsedPublicKey::new(
-        &signature::ED25519,
-        hex::decode(DISCORD_PUBLIC_KEY_STRING).expect("invalid hex for public key"),
-    );
     let mut resp = Response::new(Body::empty());
 
     match (req.method(), req.uri().path()) {
         (&Method::GET, "/") => {
             *resp.body_mut() = "Hello, world!".into();
         }
-        (&Method::POST, "/") => {
-            let timestamp = req.headers().get("x-signature-timestamp");
+        // the process is up, which it is if it's answering
+        (&Method::GET, "/healthz") => *resp.body_mut() = "ok".into(),
+        (&Method::GET, "/readyz") => return Ok(readiness(&shared)),
+        (&Method::GET, "/metrics") => return Ok(metrics(&shared)),
+        (&Method::GET, path) if path.starts_with(REPLAY_PATH) => {
+            let game_id = &path[REPLAY_PATH.len()..];
+            return Ok(admin_replay(&req, &shared, game_id));
+        }
+        (&Method::POST, path)
+            if path == "/"
+                || path
+                    .strip_prefix(INTERACTIONS_PATH)
+                    .is_some_and(|name| shared.config.app(name).is_some()) =>
+        {
+            let only = path.strip_prefix(INTERACTIONS_PATH).map(str::to_string);
+            return receive(req, &shared, only.as_deref()).await;
+        }
+        _ => {
+            *resp.status_mut() = StatusCode::NOT_FOUND;
+        }
+    }
 
-            if timestamp == None {
-                *resp.body_mut() = "No timestamp!".into();
-                *resp.status_mut() = StatusCode::BAD_REQUEST;
-                return Ok(resp);
-            }
+    Ok(resp)
+}
 
-            let timestamp_string = timestamp.unwrap().to_str();
+/// Checks an interaction is really from discord (and new), then answers it.
+/// `only` is the app it was sent to, if it was sent to one.
+async fn receive(
+    req: Request<Body>,
+    shared: &Shared,
+    only: Option<&str>,
+) -> Result<Response<Body>, magic::MagicError> {
+    let mut resp = Response::new(Body::empty());
 
-            if let Err(_e) = timestamp_string {
-                *resp.body_mut() = "Invalid timestamp.".into();
-                *resp.status_mut() = StatusCode::BAD_REQUEST;
-                return Ok(resp);
-            }
+    let timestamp = req.headers().get("x-signature-timestamp");
 
-            let signature = req.headers().get("x-signature-ed25519");
+    if timestamp.is_none() {
+        *resp.body_mut() = "No timestamp!".into();
+        *resp.status_mut() = StatusCode::BAD_REQUEST;
+        return Ok(resp);
+    }
 
-            if signature == None {
-                *resp.body_mut() = "No signature!".into();
-                *resp.status_mut() = StatusCode::BAD_REQUEST;
-                return Ok(resp);
-            }
+    let timestamp_string = timestamp.unwrap().to_str();
 
-            let signature_string = signature.unwrap().to_str();
+    if let Err(_e) = timestamp_string {
+        *resp.body_mut() = "Invalid timestamp.".into();
+        *resp.status_mut() = StatusCode::BAD_REQUEST;
+        return Ok(resp);
+    }
 
-            if let Err(_e) = signature_string {
-                *resp.body_mut() = "Invalid signature.".into();
-                *resp.status_mut() = StatusCode::BAD_REQUEST;
-                return Ok(resp);
-            }
+    let signature = req.headers().get("x-signature-ed25519");
 
-            let signature_bytes = hex::decode(signature_string.unwrap());
+    if signature.is_none() {
+        *resp.body_mut() = "No signature!".into();
+        *resp.status_mut() = StatusCode::BAD_REQUEST;
+        return Ok(resp);
+    }
 
-            if let Err(_e) = signature_bytes {
-                *resp.body_mut() = "Invalid hex for signature.".into();
-                *resp.status_mut() = StatusCode::BAD_REQUEST;
-                return Ok(resp);
-            }
+    let signature_string = signature.unwrap().to_str();
 
-            let mut verified_body: Vec<u8> = timestamp_string.unwrap().into();
-            let body = hyper::body::to_bytes(req.into_body()).await?;
-            verified_body.append(&mut body.to_vec());
+    if let Err(_e) = signature_string {
+        *resp.body_mut() = "Invalid signature.".into();
+        *resp.status_mut() = StatusCode::BAD_REQUEST;
+        return Ok(resp);
+    }
 
-            let verified = public_key.verify(&verified_body, &signature_bytes.unwrap());
+    let signature_bytes = hex::decode(signature_string.unwrap());
 
-            if let Err(_e) = verified {
-                *resp.body_mut() = "Bad signature.".into();
-                *resp.status_mut() = StatusCode::UNAUTHORIZED;
-                return Ok(resp);
-            }
+    if let Err(_e) = signature_bytes {
+        *resp.body_mut() = "Invalid hex for signature.".into();
+        *resp.status_mut() = StatusCode::BAD_REQUEST;
+        return Ok(resp);
+    }
 
-            let full_body = body.to_vec();
+    let timestamp_string = timestamp_string.unwrap().to_string();
+    let Some(body) = read_body(req.into_body(), shared.config.max_body_size).await? else {
+        shared.metrics.rejected("too_large");
+        tracing::debug!("body too large");
+        *resp.body_mut() = "Body too large.".into();
+        *resp.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+        return Ok(resp);
+    };
 
-            let body_string: &str = std::str::from_utf8(&full_body)?;
+    let signed_by = shared.signed_by(
+        only,
+        timestamp_string.as_bytes(),
+        &body,
+        &signature_bytes.unwrap(),
+    );
 
-            let p: magic::request_types::RawInteraction = serde_json::from_str(body_string)?;
+    let Some((app, db)) = signed_by else {
+        shared.metrics.signature_failed();
+        tracing::debug!("bad signature");
+        *resp.body_mut() = "Bad signature.".into();
+        *resp.status_mut() = StatusCode::UNAUTHORIZED;
+        return Ok(resp);
+    };
 
-            return if p.interaction_type == 1 {
-                *resp.body_mut() = serde_json::json!({
-                    "type": 1
-                })
-                .to_string()
-                .into();
-                Ok(resp)
-            } else {
-                let interaction = magic::request_types::Interaction::try_from(p)?;
+    // the signature only proves discord sent this at some point, so
+    // old requests being sent again are turned away here
+    let now = magic::now();
+    let sent = timestamp_string
+        .parse::<u64>()
+        .ok()
+        .filter(|sent| sent.abs_diff(now) <= shared.config.timestamp_window);
+
+    let Some(sent) = sent else {
+        shared.metrics.rejected("stale");
+        tracing::debug!(timestamp = %timestamp_string, "stale request");
+        *resp.body_mut() = "Stale request.".into();
+        *resp.status_mut() = StatusCode::UNAUTHORIZED;
+        return Ok(resp);
+    };
+
+    let body_string: &str = std::str::from_utf8(&body)?;
+
+    let p: magic::request_types::RawInteraction = serde_json::from_str(body_string)?;
+
+    if !shared.seen.first_time(p.id(), sent, now) {
+        shared.metrics.rejected("duplicate");
+        tracing::debug!(id = %p.id(), "already handled");
+        *resp.body_mut() = "Already handled.".into();
+        *resp.status_mut() = StatusCode::CONFLICT;
+        return Ok(resp);
+    }
+
+    if p.interaction_type == 1 {
+        *resp.body_mut() = serde_json::json!({
+            "type": 1
+        })
+        .to_string()
+        .into();
+        Ok(resp)
+    } else {
+        dispatch(shared, app, db, p).await
+    }
+}
+
+/// The whole body, or `None` if it's over `limit` bytes. What the client says
+/// it's sending is checked first, so a huge body is turned away before any of
+/// it is read.
+async fn read_body(mut body: Body, limit: u64) -> Result<Option<Vec<u8>>, magic::MagicError> {
+    use hyper::body::HttpBody;
 
-                Ok(Response::new(
-                    magic::handle_interaction(interaction, db)
-                        .await?
-                        .try_into()?,
-                ))
-            };
+    if body.size_hint().lower() > limit {
+        return Ok(None);
+    }
+
+    let mut bytes = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if (bytes.len() + chunk.len()) as u64 > limit {
+            return Ok(None);
         }
-        _ => {
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(Some(bytes))
+}
+
+/// Hands an interaction to its handler, keeping track of how it went.
+async fn dispatch(
+    shared: &Shared,
+    app: &App,
+    db: &Database,
+    p: magic::request_types::RawInteraction,
+) -> Result<Response<Body>, magic::MagicError> {
+    let interaction = magic::request_types::Interaction::try_from(p)?;
+    let command = interaction
+        .clone()
+        .data()
+        .map_or_else(|| "unknown".to_string(), |data| data.name().to_string());
+
+    // never the token, that'd let anyone reading the logs answer as us
+    let span = tracing::info_span!(
+        "interaction",
+        id = %interaction.id(),
+        app = %app.name,
+        guild = %interaction.clone().guild_id(),
+        channel = %interaction.clone().channel_id(),
+        user = %interaction.clone().member().user().id(),
+        command = %command,
+    );
+
+    let response = answer(
+        shared,
+        app,
+        &command,
+        span,
+        magic::handle_interaction(
+            interaction,
+            db.clone(),
+            &app.commands,
+            &shared.limits,
+            shared.discord(app),
+        ),
+    )
+    .await;
+
+    Ok(Response::new(response.try_into()?))
+}
+
+/// Runs `handler` in `span`, counting how it went. If it fails or panics, the
+/// user gets an apology and we get the details, and the connection carries on.
+async fn answer(
+    shared: &Shared,
+    app: &App,
+    command: &str,
+    span: tracing::Span,
+    handler: impl Future<Output = Result<InteractionResponse, magic::MagicError>>,
+) -> InteractionResponse {
+    let started = Instant::now();
+    // a handler that panics is answered like one that failed
+    let response = AssertUnwindSafe(handler.instrument(span.clone()))
+        .catch_unwind()
+        .await
+        .unwrap_or(Err(magic::MagicError::Panicked));
+    let took = started.elapsed();
+    shared
+        .metrics
+        .handled(&app.name, command, response.is_ok(), took);
+
+    // discord only tells the user "interaction failed" if we don't answer
+    // properly, so a failed handler still gets an answer
+    span.in_scope(|| match response {
+        Ok(response) => {
+            tracing::info!(took_ms = took.as_millis(), "handled");
+            response
+        }
+        Err(e) => {
+            let reference = error_reference();
+            tracing::error!(
+                took_ms = took.as_millis(),
+                %reference,
+                error = %e,
+                details = ?e,
+                "handler failed"
+            );
+
+            InteractionResponse::create(
+                4,
+                Data::ephemeral_content(format!(
+                    "sorry, something went wrong on our end. if it keeps happening, mention error `{}` to whoever runs this bot.",
+                    reference
+                )),
+            )
+        }
+    })
+}
+
+/// Short enough to read out, and logged with the error it's for.
+fn error_reference() -> String {
+    use ring::rand::SecureRandom;
+
+    let mut bytes = [0; 4];
+    // it's only for finding the log line, so zeroes will do if this fails
+    let _ = ring::rand::SystemRandom::new().fill(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// 200 if every app's database takes writes, 503 if one doesn't.
+fn readiness(shared: &Shared) -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+
+    let broken: Vec<&str> = shared
+        .apps()
+        .filter(|(_, db)| db.probe().is_err())
+        .map(|(app, _)| app.name.as_str())
+        .collect();
+
+    if broken.is_empty() {
+        *resp.body_mut() = "ok".into();
+    } else {
+        *resp.body_mut() = format!("can't write to: {}", broken.join(", ")).into();
+        *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    resp
+}
+
+fn metrics(shared: &Shared) -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+
+    match shared
+        .metrics
+        .render(shared.apps().map(|(app, db)| (app.name.as_str(), db)))
+    {
+        Ok(metrics) => {
+            resp.headers_mut().insert(
+                "content-type",
+                "text/plain; version=0.0.4"
+                    .parse()
+                    .expect("bad header value"),
+            );
+            *resp.body_mut() = metrics.into();
+        }
+        Err(e) => {
+            *resp.body_mut() = format!("{}", e).into();
+            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+
+    resp
+}
+
+const REPLAY_PATH: &str = "/admin/replays/";
+
+/// Whether this request has the admin token. Admin endpoints are off when
+/// `admin_token` isn't set, so this is `None` then.
+fn admin_authorized(req: &Request<Body>, config: &Config) -> Option<bool> {
+    let token = config.admin_token.as_ref()?;
+
+    Some(
+        req.headers()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| {
+                ring::constant_time::verify_slices_are_equal(given.as_bytes(), token.as_bytes())
+                    .is_ok()
+            }),
+    )
+}
+
+fn admin_replay(req: &Request<Body>, shared: &Shared, game_id: &str) -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+
+    match admin_authorized(req, &shared.config) {
+        None => {
             *resp.status_mut() = StatusCode::NOT_FOUND;
+            return resp;
+        }
+        Some(false) => {
+            *resp.body_mut() = "Bad admin token.".into();
+            *resp.status_mut() = StatusCode::UNAUTHORIZED;
+            return resp;
         }
+        Some(true) => {}
     }
 
-    Ok(resp)
+    // game ids are interaction ids, so at most one app has it
+    let replay = shared
+        .databases
+        .iter()
+        .map(|db| magic::replay::Replay::build(db, game_id))
+        .find(|replay| !matches!(replay, Ok(None)))
+        .unwrap_or(Ok(None));
+
+    match replay {
+        Ok(Some(replay)) => {
+            let headers = resp.headers_mut();
+            headers.insert(
+                "content-type",
+                "application/json".parse().expect("bad header value"),
+            );
+            if let Ok(disposition) =
+                format!("attachment; filename=\"{}\"", replay.file_name()).parse()
+            {
+                headers.insert("content-disposition", disposition);
+            }
+            *resp.body_mut() = replay.to_json().into();
+        }
+        Ok(None) => {
+            *resp.body_mut() = "No such game.".into();
+            *resp.status_mut() = StatusCode::NOT_FOUND;
+        }
+        Err(e) => {
+            *resp.body_mut() = format!("{}", e).into();
+            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+
+    resp
 }
 
-async fn error_handler(req: Request<Body>, db: Database) -> Result<Response<Body>, Infallible> {
-    match handle_request(req, db).await {
+async fn error_handler(
+    req: Request<Body>,
+    shared: Arc<Shared>,
+) -> Result<Response<Body>, Infallible> {
+    let span = tracing::info_span!("request", method = %req.method(), path = %req.uri().path());
+
+    // turned away rather than queued, so a flood can't pile up in memory
+    let Ok(_permit) = shared.requests.try_acquire() else {
+        shared.metrics.rejected("busy");
+        span.in_scope(|| tracing::warn!("too many requests at once"));
+        let mut response = Response::new("Too busy, try again soon.".into());
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        return Ok(response);
+    };
+
+    // and anything else that panics still gets a 500 instead of a dropped
+    // connection
+    let handling = AssertUnwindSafe(handle_request(req, shared.clone()).instrument(span.clone()))
+        .catch_unwind();
+    let timeout = Duration::from_secs(shared.config.request_timeout);
+    let Ok(result) = tokio::time::timeout(timeout, handling).await else {
+        // most likely a client sending its body slowly
+        shared.metrics.rejected("timed_out");
+        span.in_scope(|| tracing::warn!("request took too long"));
+        let mut response = Response::new("Took too long.".into());
+        *response.status_mut() = StatusCode::REQUEST_TIMEOUT;
+        return Ok(response);
+    };
+    let result = result.unwrap_or(Err(magic::MagicError::Panicked));
+
+    match result {
         Ok(response) => Ok(response),
         Err(err) => {
             let mut response = Response::default();
-            *response.status_mut() = StatusCode::BAD_REQUEST;
-            *response.body_mut() = format!("{}", err).into();
+            *response.status_mut() = err.status();
+
+            // only say what went wrong when it's the request's fault
+            if err.status().is_client_error() {
+                span.in_scope(|| tracing::warn!(error = %err, "bad request"));
+                *response.body_mut() = format!("{}", err).into();
+            } else {
+                span.in_scope(|| tracing::error!(error = %err, details = ?err, "request failed"));
+                *response.body_mut() = "Something went wrong.".into();
+            }
 
             Ok(response)
         }
     }
 }
 
-#[tokio::main]
-async fn main() {
-    let tobogan = sled::open("sled.data").expect("was not able to open sled's file");
-    let db = Database::make(tobogan);
+// where `db migrate-sqlite` copies from, unless it's told otherwise
+#[cfg(feature = "sqlite")]
+const SLED_PATH: &str = "sled.data";
 
-    let make_svc = make_service_fn(move |_| {
-        let state = db.clone();
-        async { Ok::<_, Infallible>(service_fn(move |req| error_handler(req, state.clone()))) }
+const SWEEP_EVERY: Duration = Duration::from_mins(5);
+
+// certbot and friends renew well ahead of expiry, so this can be lazy
+#[cfg(feature = "tls")]
+const CERTIFICATE_CHECK_EVERY: Duration = Duration::from_mins(1);
+
+fn snapshots(app: &App, config: &Config) -> Snapshots {
+    Snapshots::new(app.snapshot_dir.clone(), config.snapshots_kept)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_database(app: &App) -> Database {
+    let tobogan = sled::open(&app.database).expect("was not able to open sled's file");
+    Database::make(tobogan)
+}
+
+#[cfg(feature = "sqlite")]
+fn open_database(app: &App) -> Database {
+    Database::make_sqlite(
+        magic::sqlite::Sqlite::open(&app.database).expect("was not able to open sqlite's file"),
+    )
+}
+
+// one-shot, run this before switching a deployment over to sqlite
+#[cfg(feature = "sqlite")]
+fn migrate_sqlite(app: &App, sled_path: &str) {
+    let from = Database::make(sled::open(sled_path).expect("was not able to open sled's file"));
+    let to =
+        magic::sqlite::Sqlite::open(&app.database).expect("was not able to open sqlite's file");
+
+    if !to.is_empty().expect("was not able to read sqlite's file") {
+        eprintln!(
+            "{} already has data in it, refusing to migrate.",
+            app.database.display()
+        );
+        std::process::exit(1);
+    }
+
+    match magic::sqlite::migrate_from_sled(&from, &to) {
+        Ok(migration) => {
+            println!(
+                "copied {} lobbies, {} players and {} other records to {}.",
+                migration.lobbies,
+                migration.players,
+                migration.records,
+                app.database.display()
+            );
+            for (player, lobby) in migration.dangling_players {
+                println!(
+                    "dropped {}, whose lobby {} no longer exists.",
+                    player, lobby
+                );
+            }
+        }
+        Err(e) => {
+            eprintln!("migration failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn check_database(app: &App, repair: bool) {
+    let db = open_database(app);
+    let problems = magic::check::check(&db).expect("was not able to read the database");
+
+    let mut remaining = 0;
+    for problem in problems {
+        if repair
+            && problem
+                .repair(&db)
+                .expect("was not able to repair the database")
+        {
+            println!("fixed: {}", problem);
+        } else {
+            println!("{}", problem);
+            remaining += 1;
+        }
+    }
+
+    if remaining > 0 {
+        eprintln!("{} problems left.", remaining);
+        std::process::exit(1);
+    }
+}
+
+fn export_database(app: &App, path: Option<&str>) {
+    let dump =
+        magic::export::export(&open_database(app)).expect("was not able to read the database");
+
+    let mut out: Box<dyn Write> = path.map_or_else(
+        || Box::new(std::io::stdout()) as Box<dyn Write>,
+        |path| {
+            Box::new(std::fs::File::create(path).expect("was not able to create the export file"))
+        },
+    );
+    serde_json::to_writer_pretty(&mut out, &dump)
+        .map_err(std::io::Error::from)
+        .and_then(|()| writeln!(out))
+        .expect("was not able to write the export");
+}
+
+fn import_database(app: &App, path: &str) {
+    let file = std::fs::File::open(path).expect("was not able to open the export file");
+    let dump: magic::export::Dump =
+        serde_json::from_reader(std::io::BufReader::new(file)).expect("bad export file");
+    let db = open_database(app);
+
+    if !db.is_empty().expect("was not able to read the database") {
+        eprintln!("the database already has data in it, refusing to import.");
+        std::process::exit(1);
+    }
+
+    magic::export::import(&db, &dump).expect("was not able to import");
+    println!(
+        "imported {} lobbies and {} players.",
+        dump.lobbies.len(),
+        dump.players.len()
+    );
+}
+
+fn list_snapshots(app: &App, config: &Config) {
+    for snapshot in snapshots(app, config)
+        .list()
+        .expect("was not able to list snapshots")
+    {
+        println!("{}", snapshot.display());
+    }
+}
+
+fn restore_snapshot(app: &App, config: &Config, name: &str) {
+    let snapshots = snapshots(app, config);
+    let available = snapshots.list().expect("was not able to list snapshots");
+
+    let snapshot = if name == "latest" {
+        available.last()
+    } else {
+        available
+            .iter()
+            .find(|path| path.as_os_str() == name || path.file_name() == Some(name.as_ref()))
+    };
+
+    let snapshot = snapshot.unwrap_or_else(|| {
+        eprintln!("no such snapshot, see `magic db snapshots`.");
+        std::process::exit(1);
     });
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8000));
+    let backup = snapshots
+        .restore(&open_database(app), snapshot)
+        .expect("was not able to restore the snapshot");
+    println!(
+        "restored {}, the old state is in {}.",
+        snapshot.display(),
+        backup.display()
+    );
+}
+
+// steps through a replay one event at a time
+fn step_replay(path: &str) {
+    let file = std::fs::File::open(path).expect("was not able to open the replay");
+    let replay: magic::replay::Replay =
+        serde_json::from_reader(std::io::BufReader::new(file)).expect("bad replay file");
+
+    println!(
+        "{}",
+        magic::replay::header(&replay.game_id, &replay.summary)
+    );
+    println!("(enter for the next event, q to stop)");
+
+    let stdin = std::io::stdin();
+    for logged in &replay.events {
+        println!("{}", magic::replay::line(&replay.summary, logged));
+
+        // at the end of input, this just prints the rest
+        let mut input = String::new();
+        if stdin.read_line(&mut input).is_ok() && input.trim() == "q" {
+            return;
+        }
+    }
+}
+
+async fn serve(databases: Vec<Database>, config: Config) {
+    // panics are caught per request, so log them like everything else, in the
+    // span of the request that hit them
+    std::panic::set_hook(Box::new(|info| {
+        tracing::error!(panic = %info, "panicked");
+    }));
+
+    if let Some(app) = config.apps.iter().find(|app| !app.verifier.has_keys()) {
+        tracing::error!(app = %app.name, "a public_key is needed to serve, see the readme");
+        std::process::exit(2);
+    }
+
+    // everything that's running is told to stop through this
+    let (stop, stopping) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        tracing::info!("shutting down");
+        let _ = stop.send(true);
+    });
 
-    let server = Server::bind(&addr)
+    // only needed to tell channels about things nobody asked about, like a
+    // lobby closing or day breaking
+    let discords = config
+        .apps
+        .iter()
+        .map(|app| {
+            if app.bot_token.is_none() {
+                tracing::warn!(
+                    app = %app.name,
+                    "no bot_token, so closed lobbies, daybreaks and replays won't be announced"
+                );
+            }
+            app.bot_token.clone().map(Discord::new)
+        })
+        .collect::<Vec<_>>();
+
+    let mut background = vec![];
+    for ((app, db), discord) in config.apps.iter().zip(&databases).zip(&discords) {
+        background.push(tokio::spawn(magic::snapshot::run(
+            snapshots(app, &config),
+            db.clone(),
+            Duration::from_secs(config.snapshot_every),
+            stopping.clone(),
+        )));
+
+        background.push(tokio::spawn(magic::sweeper::run(
+            db.clone(),
+            discord.clone(),
+            SWEEP_EVERY,
+            stopping.clone(),
+        )));
+    }
+
+    let shared = Arc::new(Shared {
+        seen: SeenInteractions::new(config.timestamp_window),
+        metrics: Metrics::default(),
+        requests: Semaphore::new(
+            usize::try_from(config.max_concurrent_requests)
+                .unwrap_or(usize::MAX)
+                .min(Semaphore::MAX_PERMITS),
+        ),
+        limits: RateLimits::new(config.user_rate_limit, config.guild_rate_limit),
+        config,
+        databases,
+        discords,
+    });
+
+    let deadline = listen(shared.clone(), stopping).await;
+
+    // a sweep or snapshot that's underway gets whatever the requests left of
+    // the deadline
+    let finishing = futures::future::join_all(background);
+    if tokio::time::timeout_at(deadline, finishing).await.is_err() {
+        tracing::warn!("a sweep or snapshot didn't finish in time, leaving it");
+    }
+
+    for (app, db) in shared.apps() {
+        if let Err(e) = db.flush() {
+            tracing::error!(app = %app.name, error = %e, "was not able to flush the database");
+        }
+    }
+
+    for (app, db) in shared.apps() {
+        if let Err(e) = snapshots(app, &shared.config).take(db) {
+            tracing::error!(
+                app = %app.name,
+                error = %e,
+                "was not able to take a snapshot on the way out"
+            );
+        }
+    }
+}
+
+/// Answers requests, over https if there's a certificate, until it's time to
+/// shut down. Returns when the rest of shutting down has to be done by.
+async fn listen(shared: Arc<Shared>, stopping: watch::Receiver<bool>) -> tokio::time::Instant {
+    let bind = shared.config.bind;
+    // so a client can't hold a connection open by never finishing its headers
+    let header_timeout = Duration::from_secs(shared.config.header_timeout);
+
+    #[cfg(feature = "tls")]
+    if let Some(tls) = &shared.config.tls {
+        let certificates = match magic::tls::Certificates::load(tls.cert.clone(), tls.key.clone()) {
+            Ok(certificates) => Arc::new(certificates),
+            Err(e) => {
+                tracing::error!(error = %e, "was not able to load the tls certificate");
+                std::process::exit(2);
+            }
+        };
+        let listener = match tokio::net::TcpListener::bind(bind).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::error!(%bind, error = %e, "was not able to bind");
+                std::process::exit(1);
+            }
+        };
+        tokio::spawn(magic::tls::watch(
+            certificates.clone(),
+            CERTIFICATE_CHECK_EVERY,
+        ));
+
+        tracing::info!(%bind, "listening with tls");
+        let incoming = magic::tls::incoming(listener, certificates, header_timeout);
+        return run(
+            Server::builder(incoming).http1_header_read_timeout(header_timeout),
+            shared,
+            stopping,
+        )
+        .await;
+    }
+
+    tracing::info!(%bind, "listening");
+    run(
+        Server::bind(&bind).http1_header_read_timeout(header_timeout),
+        shared,
+        stopping,
+    )
+    .await
+}
+
+/// Serves until `stopping` turns true, then stops taking connections and
+/// gives the requests being worked on until `shutdown_timeout` from then to
+/// finish. Returns that deadline, since everything else that has to finish
+/// before exiting shares it.
+async fn run<I>(
+    server: hyper::server::Builder<I>,
+    shared: Arc<Shared>,
+    stopping: watch::Receiver<bool>,
+) -> tokio::time::Instant
+where
+    I: hyper::server::accept::Accept,
+    I::Conn: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let shutdown_timeout = Duration::from_secs(shared.config.shutdown_timeout);
+    let make_svc = make_service_fn(move |_| {
+        let shared = shared.clone();
+        async { Ok::<_, Infallible>(service_fn(move |req| error_handler(req, shared.clone()))) }
+    });
+
+    let server = server
         .serve(make_svc)
-        .with_graceful_shutdown(shutdown_signal());
+        .with_graceful_shutdown(stopped(stopping.clone()));
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => {
+            if let Err(e) = result {
+                tracing::error!(error = %e, "server error");
+            }
+            return tokio::time::Instant::now() + shutdown_timeout;
+        }
+        () = stopped(stopping) => {}
+    }
+
+    let deadline = tokio::time::Instant::now() + shutdown_timeout;
+    match tokio::time::timeout_at(deadline, server).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => tracing::error!(error = %e, "server error"),
+        Err(_) => tracing::warn!("requests were still being worked on, leaving them"),
+    }
+
+    deadline
+}
+
+// every command logs through here, server or not. it all goes to stderr, so
+// `db export` without a file still gets stdout to itself.
+fn init_logging(config: &Config) {
+    use tracing_subscriber::filter::{LevelFilter, Targets};
+    use tracing_subscriber::prelude::*;
+
+    let level = match config.log_level {
+        LogLevel::Error => LevelFilter::ERROR,
+        LogLevel::Warn => LevelFilter::WARN,
+        LogLevel::Info => LevelFilter::INFO,
+        LogLevel::Debug => LevelFilter::DEBUG,
+    };
+    // sled and hyper are chatty, and only their problems are ours
+    let filter = Targets::new()
+        .with_target("magic", level)
+        .with_default(level.min(LevelFilter::WARN));
+    let logs = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+
+    match config.log_format {
+        LogFormat::Pretty => tracing_subscriber::registry()
+            .with(logs.with_filter(filter))
+            .init(),
+        LogFormat::Json => tracing_subscriber::registry()
+            .with(logs.json().with_filter(filter))
+            .init(),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = magic::config::parse_flags(&args)
+        .and_then(|(flags, rest)| Ok((Config::load(flags.file, flags.settings)?, flags.app, rest)));
+    let (config, app, args) = config.unwrap_or_else(|e| {
+        eprint!("{}", e);
+        std::process::exit(2);
+    });
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    init_logging(&config);
+
+    // the `db` commands work on one app's data at a time
+    let app = app.map_or_else(
+        || config.main_app(),
+        |name| {
+            config.app(&name).unwrap_or_else(|| {
+                eprintln!("there's no app called {} in the configuration.", name);
+                std::process::exit(2);
+            })
+        },
+    );
+
+    match args.as_slice() {
+        [] => {
+            let mut databases = vec![];
+            for app in &config.apps {
+                let db = open_database(app);
+                let upgraded = db
+                    .migrate_records()
+                    .expect("was not able to migrate old records");
+                if upgraded > 0 {
+                    tracing::info!(app = %app.name, upgraded, "upgraded old records");
+                }
+                let indexed = magic::history::index_unfinished(&db)
+                    .expect("was not able to index unfinished games");
+                if indexed > 0 {
+                    tracing::info!(app = %app.name, indexed, "indexed unfinished games");
+                }
+
+                let problems =
+                    magic::check::check(&db).expect("was not able to check the database");
+                for problem in &problems {
+                    tracing::warn!(app = %app.name, %problem, "database problem");
+                }
+                if !problems.is_empty() {
+                    tracing::warn!(
+                        "run `magic --app {} db check --repair` to fix what can be fixed",
+                        app.name
+                    );
+                }
+
+                databases.push(db);
+            }
+
+            serve(databases, config).await;
+        }
+        ["db", "check"] => check_database(app, false),
+        ["db", "check", "--repair"] => check_database(app, true),
+        ["db", "export"] => export_database(app, None),
+        ["db", "export", path] => export_database(app, Some(path)),
+        ["db", "import", path] => import_database(app, path),
+        ["db", "snapshots"] => list_snapshots(app, &config),
+        ["db", "restore", snapshot] => restore_snapshot(app, &config, snapshot),
+        ["replay", path] => step_replay(path),
+        #[cfg(feature = "sqlite")]
+        ["db", "migrate-sqlite"] => migrate_sqlite(app, SLED_PATH),
+        #[cfg(feature = "sqlite")]
+        ["db", "migrate-sqlite", sled_path] => migrate_sqlite(app, sled_path),
+        _ => {
+            eprintln!(
+                "usage: magic [--config <file>] [--app <name>] [--public-key <hex>] [--bind <addr>] [--database <path>] [--snapshot-dir <dir>] [--log-level <level>] [--log-format <pretty|json>] [--timestamp-window <secs>] [--max-body-size <bytes>] [--header-timeout <secs>] [--request-timeout <secs>] [--max-concurrent-requests <n>] [--shutdown-timeout <secs>] [--user-rate-limit <n>] [--guild-rate-limit <n>] [--snapshot-every <secs>] [--snapshots-kept <n>] [--tls-cert <file>] [--tls-key <file>] [--admin-token <token>] [--commands-<command> <id>] [db check [--repair] | db export [file] | db import <file> | db snapshots | db restore <snapshot|latest> | db migrate-sqlite [sled dir] | replay <file>]"
+            );
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    const COMMANDS: &str = "create_lobby = \"1\"\njoin_lobby = \"2\"\nkill_player = \"3\"\nvote_player = \"4\"\nleave_lobby = \"5\"";
+
+    fn key(seed: u8) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap()
+    }
+
+    fn public_key(seed: u8) -> String {
+        hex::encode(key(seed).public_key().as_ref())
+    }
+
+    /// Everything a request needs, for a config with extra settings on top of
+    /// the main app signing with key 1.
+    fn shared(extra: &str) -> Arc<Shared> {
+        let file = format!(
+            "public_key = {:?}\n{}\n[commands]\n{}",
+            public_key(1),
+            extra,
+            COMMANDS
+        );
+        let config = Config::validate(toml::from_str(&file).unwrap()).unwrap();
+        let databases = config
+            .apps
+            .iter()
+            .map(|_| Database::make(sled::Config::new().temporary(true).open().unwrap()))
+            .collect();
+
+        Arc::new(Shared {
+            seen: SeenInteractions::new(config.timestamp_window),
+            metrics: Metrics::default(),
+            requests: Semaphore::new(usize::try_from(config.max_concurrent_requests).unwrap()),
+            limits: RateLimits::new(config.user_rate_limit, config.guild_rate_limit),
+            discords: config.apps.iter().map(|_| None).collect(),
+            databases,
+            config,
+        })
+    }
+
+    fn signed(path: &str, seed: u8, body: &str) -> Request<Body> {
+        let timestamp = magic::now().to_string();
+        let signature = key(seed).sign(format!("{}{}", timestamp, body).as_bytes());
+
+        Request::post(path)
+            .header("x-signature-timestamp", timestamp)
+            .header("x-signature-ed25519", hex::encode(signature.as_ref()))
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    fn ping(id: &str) -> String {
+        serde_json::json!({ "id": id, "type": 1, "token": "token", "version": 1 }).to_string()
+    }
+
+    async fn send(shared: &Arc<Shared>, req: Request<Body>) -> StatusCode {
+        handle_request(req, shared.clone()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn interactions_go_to_the_app_they_were_sent_to() {
+        let shared = shared(&format!(
+            "[apps.beta]\npublic_key = {:?}\n[apps.beta.commands]\n{}",
+            public_key(2),
+            COMMANDS
+        ));
+
+        let beta = signed("/interactions/beta", 2, &ping("1"));
+        assert_eq!(send(&shared, beta).await, StatusCode::OK);
+        // the main app's key doesn't get into beta
+        let main = signed("/interactions/beta", 1, &ping("2"));
+        assert_eq!(send(&shared, main).await, StatusCode::UNAUTHORIZED);
+        // but either gets in through /
+        assert_eq!(
+            send(&shared, signed("/", 1, &ping("3"))).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(&shared, signed("/", 2, &ping("4"))).await,
+            StatusCode::OK
+        );
+
+        let unknown = signed("/interactions/gamma", 2, &ping("5"));
+        assert_eq!(send(&shared, unknown).await, StatusCode::NOT_FOUND);
+    }
+
+    /// The error reference in an apology, if that's what `response` is.
+    fn reference(response: &serde_json::Value) -> Option<String> {
+        let content = response["data"]["content"].as_str()?;
+        let reference = content.split('`').nth(1)?;
+        assert_eq!(response["data"]["flags"], 64, "apologies are just for them");
+        Some(reference.to_string())
+    }
+
+    #[tokio::test]
+    async fn failed_handlers_still_answer() {
+        let shared = shared("");
+        // no command, so the handler gives up on it
+        let body = serde_json::json!({
+            "id": "1",
+            "type": 2,
+            "guild_id": "10",
+            "channel_id": "20",
+            "member": {
+                "user": {
+                    "id": "30",
+                    "username": "30",
+                    "discriminator": "0",
+                    "public_flags": 0,
+                },
+                "roles": [],
+                "deaf": false,
+                "mute": false,
+                "permissions": "0",
+            },
+            "token": "token",
+            "version": 1,
+        });
+
+        let resp = handle_request(signed("/", 1, &body.to_string()), shared.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let response: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(response["type"], 4);
+        let reference = reference(&response).unwrap();
+        assert_eq!(reference.len(), 8);
+        assert!(reference.chars().all(|c| c.is_ascii_hexdigit()));
+
+        let rendered = shared
+            .metrics
+            .render(shared.apps().map(|(app, db)| (app.name.as_str(), db)))
+            .unwrap();
+        assert!(rendered.contains(
+            "magic_requests_total{app=\"main\",command=\"unknown\",outcome=\"error\"} 1\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn panicking_handlers_are_answered_like_failed_ones() {
+        let shared = shared("");
+        let app = shared.config.main_app();
+
+        let response = answer(&shared, app, "boom", tracing::Span::none(), async {
+            panic!("boom")
+        })
+        .await;
+        assert!(reference(&serde_json::to_value(response).unwrap()).is_some());
+
+        // and everything carries on after
+        let after = answer(&shared, app, "fine", tracing::Span::none(), async {
+            Ok(InteractionResponse::create(
+                4,
+                Data::content("ok".to_string()),
+            ))
+        })
+        .await;
+        assert!(reference(&serde_json::to_value(after).unwrap()).is_none());
+        assert_eq!(
+            send(&shared, signed("/", 1, &ping("1"))).await,
+            StatusCode::OK
+        );
+    }
+
+    /// What a client would get back, limits and all.
+    async fn serve(shared: &Arc<Shared>, req: Request<Body>) -> StatusCode {
+        error_handler(req, shared.clone()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn bodies_over_the_limit_are_turned_away() {
+        let shared = shared("max_body_size = 64");
+
+        assert_eq!(
+            serve(&shared, signed("/", 1, &ping("1"))).await,
+            StatusCode::OK
+        );
+        // still a ping, just a long one
+        let padded = format!("{:100}", ping("2"));
+        assert_eq!(
+            serve(&shared, signed("/", 1, &padded)).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        // and when it doesn't say how long it is, once it's read too much
+        assert_eq!(
+            read_body(streamed(&["12345", "67890"]), 8).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            read_body(streamed(&["12345"]), 8).await.unwrap(),
+            Some(b"12345".to_vec())
+        );
+    }
+
+    /// A body sent in `chunks`, without saying how long it'll be.
+    fn streamed(chunks: &[&'static str]) -> Body {
+        let (mut sender, body) = Body::channel();
+        let chunks = chunks.to_vec();
+        tokio::spawn(async move {
+            for chunk in chunks {
+                // fails once the body's been given up on
+                if sender.send_data(chunk.into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        body
+    }
+
+    #[tokio::test]
+    async fn slow_requests_time_out() {
+        let shared = shared("request_timeout = 1");
+
+        // a body that never finishes arriving
+        let (_sender, body) = Body::channel();
+        let mut req = signed("/", 1, &ping("1"));
+        *req.body_mut() = body;
+
+        assert_eq!(serve(&shared, req).await, StatusCode::REQUEST_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn requests_past_the_limit_are_turned_away() {
+        let shared = shared("max_concurrent_requests = 1");
+
+        let held = shared.requests.try_acquire().unwrap();
+        assert_eq!(
+            serve(&shared, signed("/", 1, &ping("1"))).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
 
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
+        drop(held);
+        assert_eq!(
+            serve(&shared, signed("/", 1, &ping("2"))).await,
+            StatusCode::OK
+        );
     }
 }
@@ -0,0 +1,307 @@
+//! Counters for `/metrics`, in Prometheus' text format. There's only a handful
+//! of them, so they're kept by hand instead of pulling in a client library.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::{history, Database, MagicError};
+
+/// Upper bounds of the latency buckets, in seconds.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+pub struct Metrics {
+    counts: Mutex<Counts>,
+}
+
+#[derive(Default)]
+struct Counts {
+    /// By app, command and outcome.
+    requests: BTreeMap<(String, String, &'static str), u64>,
+    /// Requests turned away before reaching a handler, by reason.
+    rejected: BTreeMap<&'static str, u64>,
+    signature_failures: u64,
+    /// Handler latency by app and command.
+    latency: BTreeMap<(String, String), Histogram>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// How many fell in each of the `BUCKETS`, not counting earlier ones.
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+impl Metrics {
+    fn counts(&self) -> std::sync::MutexGuard<'_, Counts> {
+        self.counts.lock().expect("metrics were poisoned")
+    }
+
+    /// A request that made it to a handler. `ok` is whether the handler
+    /// returned a response.
+    pub fn handled(&self, app: &str, command: &str, ok: bool, took: Duration) {
+        let mut counts = self.counts();
+        let outcome = if ok { "ok" } else { "error" };
+
+        *counts
+            .requests
+            .entry((app.to_string(), command.to_string(), outcome))
+            .or_default() += 1;
+        counts
+            .latency
+            .entry((app.to_string(), command.to_string()))
+            .or_default()
+            .observe(took.as_secs_f64());
+    }
+
+    pub fn rejected(&self, reason: &'static str) {
+        *self.counts().rejected.entry(reason).or_default() += 1;
+    }
+
+    pub fn signature_failed(&self) {
+        self.counts().signature_failures += 1;
+    }
+
+    /// Everything in Prometheus' text format. The lobby and game gauges are
+    /// read from each app's database as this is called. Both are counted
+    /// without decoding anything, so one bad record doesn't fail every
+    /// scrape, and games come from the `unfinished_games` index rather than
+    /// every summary there's ever been.
+    pub fn render<'a>(
+        &self,
+        apps: impl Iterator<Item = (&'a str, &'a Database)>,
+    ) -> Result<String, MagicError> {
+        let mut out = String::new();
+
+        // gauges first, so a slow database doesn't hold the lock
+        let mut lobbies = String::new();
+        let mut games = String::new();
+        let mut conflicts = String::new();
+        for (app, db) in apps {
+            line(
+                &mut lobbies,
+                "magic_lobbies",
+                &[("app", app)],
+                db.count_lobbies()?,
+            );
+            line(
+                &mut games,
+                "magic_games_unfinished",
+                &[("app", app)],
+                db.count(history::UNFINISHED_TREE)?,
+            );
+            line(
+                &mut conflicts,
+                "magic_transaction_conflicts_total",
+                &[("app", app)],
+                db.conflicts(),
+            );
+        }
+
+        header(&mut out, "magic_lobbies", "gauge", "Lobbies that are open.");
+        out.push_str(&lobbies);
+        header(
+            &mut out,
+            "magic_games_unfinished",
+            "gauge",
+            "Games in the history that haven't ended.",
+        );
+        out.push_str(&games);
+        header(
+            &mut out,
+            "magic_transaction_conflicts_total",
+            "counter",
+            "Transactions that had to be retried because of another one.",
+        );
+        out.push_str(&conflicts);
+
+        let counts = self.counts();
+
+        header(
+            &mut out,
+            "magic_requests_total",
+            "counter",
+            "Interactions handled, by command and whether it went ok.",
+        );
+        for ((app, command, outcome), count) in &counts.requests {
+            line(
+                &mut out,
+                "magic_requests_total",
+                &[("app", app), ("command", command), ("outcome", outcome)],
+                count,
+            );
+        }
+
+        header(
+            &mut out,
+            "magic_rejected_requests_total",
+            "counter",
+            "Requests turned away before being handled, by reason.",
+        );
+        for (reason, count) in &counts.rejected {
+            line(
+                &mut out,
+                "magic_rejected_requests_total",
+                &[("reason", reason)],
+                count,
+            );
+        }
+
+        header(
+            &mut out,
+            "magic_signature_failures_total",
+            "counter",
+            "Interactions whose signature didn't check out.",
+        );
+        line(
+            &mut out,
+            "magic_signature_failures_total",
+            &[],
+            counts.signature_failures,
+        );
+
+        header(
+            &mut out,
+            "magic_handler_seconds",
+            "histogram",
+            "How long handlers took, by command.",
+        );
+        for ((app, command), histogram) in &counts.latency {
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                line(
+                    &mut out,
+                    "magic_handler_seconds_bucket",
+                    &[
+                        ("app", app),
+                        ("command", command),
+                        ("le", &bound.to_string()),
+                    ],
+                    cumulative,
+                );
+            }
+            let labels = [("app", app.as_str()), ("command", command.as_str())];
+            line(
+                &mut out,
+                "magic_handler_seconds_bucket",
+                &[labels[0], labels[1], ("le", "+Inf")],
+                histogram.count,
+            );
+            line(
+                &mut out,
+                "magic_handler_seconds_sum",
+                &labels,
+                histogram.sum,
+            );
+            line(
+                &mut out,
+                "magic_handler_seconds_count",
+                &labels,
+                histogram.count,
+            );
+        }
+
+        Ok(out)
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn line(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+    let _ = write!(out, "{}", name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(label, value)| {
+                format!(
+                    "{}=\"{}\"",
+                    label,
+                    value
+                        .replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace('\n', "\\n")
+                )
+            })
+            .collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unreadable_records_dont_fail_the_scrape() {
+        let sled = sled::Config::new().temporary(true).open().unwrap();
+        let db = Database::make(sled.clone());
+        sled.open_tree("lobbies")
+            .unwrap()
+            .insert("broken", &b"not a lobby"[..])
+            .unwrap();
+        sled.open_tree(history::GAMES_TREE)
+            .unwrap()
+            .insert("broken", &b"not a game"[..])
+            .unwrap();
+
+        let rendered = Metrics::default()
+            .render(std::iter::once(("app", &db)))
+            .unwrap();
+        assert!(rendered.contains("magic_lobbies{app=\"app\"} 1\n"));
+        assert!(rendered.contains("magic_games_unfinished{app=\"app\"} 0\n"));
+    }
+
+    #[test]
+    fn games_count_until_they_end() {
+        let db = Database::make(sled::Config::new().temporary(true).open().unwrap());
+        let unfinished = |db: &Database| {
+            let rendered = Metrics::default()
+                .render(std::iter::once(("app", db)))
+                .unwrap();
+            let line = "magic_games_unfinished{app=\"app\"} ";
+            let at = rendered.find(line).unwrap() + line.len();
+            rendered[at..].lines().next().unwrap().to_string()
+        };
+        let created = |channel_id: &str| history::Event::Created {
+            guild_id: "1".into(),
+            channel_id: channel_id.into(),
+            creator: "2".into(),
+        };
+
+        history::record(&db, "10", created("3")).unwrap();
+        history::record(&db, "11", created("4")).unwrap();
+        assert_eq!(unfinished(&db), "2");
+
+        history::record(&db, "10", history::Event::Disbanded).unwrap();
+        // ending it again doesn't count twice
+        history::record(&db, "10", history::Event::Disbanded).unwrap();
+        assert_eq!(unfinished(&db), "1");
+
+        // games from before the index was kept are found at startup
+        db.remove(history::UNFINISHED_TREE, "11").unwrap();
+        assert_eq!(unfinished(&db), "0");
+        assert_eq!(history::index_unfinished(&db).unwrap(), 1);
+        assert_eq!(history::index_unfinished(&db).unwrap(), 0);
+        assert_eq!(unfinished(&db), "1");
+    }
+}
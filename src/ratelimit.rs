@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// how often buckets that have filled back up are forgotten
+const FORGET_EVERY: Duration = Duration::from_secs(60);
+
+/// Token buckets for each user and each guild, so one person (or one server)
+/// spamming commands can't keep the database busy. A bucket holds a minute's
+/// worth of interactions and refills at that rate.
+pub struct RateLimits {
+    /// Interactions a minute, which is also how many a bucket holds.
+    per_user: f64,
+    per_guild: f64,
+    buckets: Mutex<Buckets>,
+}
+
+/// Which limit was hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limited {
+    User,
+    Guild,
+}
+
+struct Buckets {
+    users: HashMap<String, Bucket>,
+    guilds: HashMap<String, Bucket>,
+    forgotten_at: Instant,
+}
+
+#[derive(Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// How full it'd be at `now`, out of `per_minute`.
+    fn tokens(self, per_minute: f64, now: Instant) -> f64 {
+        let refilled = now.duration_since(self.updated).as_secs_f64() * per_minute / 60.0;
+        (self.tokens + refilled).min(per_minute)
+    }
+}
+
+impl RateLimits {
+    pub fn new(per_user: u32, per_guild: u32) -> Self {
+        Self {
+            per_user: per_user.into(),
+            per_guild: per_guild.into(),
+            buckets: Mutex::new(Buckets {
+                users: HashMap::new(),
+                guilds: HashMap::new(),
+                forgotten_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes an interaction out of both buckets, unless either is empty. Then
+    /// neither is touched, and this says which one it was.
+    pub fn take(&self, user_id: &str, guild_id: &str, now: Instant) -> Result<(), Limited> {
+        let mut buckets = self.buckets.lock().expect("rate limits were poisoned");
+
+        if now.duration_since(buckets.forgotten_at) >= FORGET_EVERY {
+            // a full bucket is the same as no bucket
+            let (per_user, per_guild) = (self.per_user, self.per_guild);
+            buckets
+                .users
+                .retain(|_, bucket| bucket.tokens(per_user, now) < per_user);
+            buckets
+                .guilds
+                .retain(|_, bucket| bucket.tokens(per_guild, now) < per_guild);
+            buckets.forgotten_at = now;
+        }
+
+        let user = buckets
+            .users
+            .get(user_id)
+            .map_or(self.per_user, |bucket| bucket.tokens(self.per_user, now));
+        let guild = buckets
+            .guilds
+            .get(guild_id)
+            .map_or(self.per_guild, |bucket| bucket.tokens(self.per_guild, now));
+
+        if user < 1.0 {
+            return Err(Limited::User);
+        }
+        if guild < 1.0 {
+            return Err(Limited::Guild);
+        }
+
+        buckets.users.insert(
+            user_id.to_string(),
+            Bucket {
+                tokens: user - 1.0,
+                updated: now,
+            },
+        );
+        buckets.guilds.insert(
+            guild_id.to_string(),
+            Bucket {
+                tokens: guild - 1.0,
+                updated: now,
+            },
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seconds(seconds: u64) -> Duration {
+        Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn users_and_guilds_have_limits_of_their_own() {
+        let limits = RateLimits::new(3, 5);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limits.take("a", "1", now), Ok(()));
+        }
+        assert_eq!(limits.take("a", "1", now), Err(Limited::User));
+        // which follows them to other guilds
+        assert_eq!(limits.take("a", "2", now), Err(Limited::User));
+
+        // being turned away didn't cost the guild anything
+        assert_eq!(limits.take("b", "1", now), Ok(()));
+        assert_eq!(limits.take("b", "1", now), Ok(()));
+        assert_eq!(limits.take("c", "1", now), Err(Limited::Guild));
+        assert_eq!(limits.take("c", "2", now), Ok(()));
+    }
+
+    #[test]
+    fn buckets_refill_a_little_at_a_time() {
+        // one a second
+        let limits = RateLimits::new(60, 1000);
+        let start = Instant::now();
+
+        for _ in 0..60 {
+            assert_eq!(limits.take("a", "1", start), Ok(()));
+        }
+        assert_eq!(limits.take("a", "1", start), Err(Limited::User));
+        assert_eq!(
+            limits.take("a", "1", start + Duration::from_millis(500)),
+            Err(Limited::User)
+        );
+
+        assert_eq!(limits.take("a", "1", start + seconds(1)), Ok(()));
+        assert_eq!(
+            limits.take("a", "1", start + seconds(1)),
+            Err(Limited::User)
+        );
+
+        // but never past full, however long it's been
+        let later = start + seconds(3600);
+        for _ in 0..60 {
+            assert_eq!(limits.take("a", "1", later), Ok(()));
+        }
+        assert_eq!(limits.take("a", "1", later), Err(Limited::User));
+    }
+
+    #[test]
+    fn full_buckets_are_forgotten() {
+        let limits = RateLimits::new(3, 1000);
+        let start = Instant::now();
+        let users = || {
+            let buckets = limits.buckets.lock().unwrap();
+            let mut users: Vec<String> = buckets.users.keys().cloned().collect();
+            users.sort();
+            users
+        };
+
+        assert_eq!(limits.take("a", "1", start), Ok(()));
+        for _ in 0..3 {
+            assert_eq!(limits.take("b", "2", start + seconds(59)), Ok(()));
+        }
+        assert_eq!(users(), ["a", "b"]);
+
+        // a's has filled back up, b's hasn't yet
+        assert_eq!(limits.take("c", "3", start + FORGET_EVERY), Ok(()));
+        assert_eq!(users(), ["b", "c"]);
+        // guilds' fill up much faster, so only the newest is left
+        let guilds: Vec<String> = limits
+            .buckets
+            .lock()
+            .unwrap()
+            .guilds
+            .keys()
+            .cloned()
+            .collect();
+        assert_eq!(guilds, ["3"]);
+        // and forgetting b's didn't give it anything back
+        assert_eq!(
+            limits.take("b", "2", start + FORGET_EVERY),
+            Err(Limited::User)
+        );
+    }
+}
@@ -0,0 +1,141 @@
+//! Elo ratings, kept separately for each faction, since being a good
+//! magician says nothing about being a good villager.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::encoding::{Upgrade, Versioned};
+use crate::game::{Faction, GameResult};
+use crate::{Database, MagicError};
+
+pub const TREE: &str = "ratings";
+
+pub const INITIAL: f64 = 1000.0;
+/// How far one game can move a rating.
+pub const K: f64 = 32.0;
+
+/// The chance a player rated `rating` beats an opponent rated `opponent`.
+pub fn expected(rating: f64, opponent: f64) -> f64 {
+    1.0 / (1.0 + 10_f64.powf((opponent - rating) / 400.0))
+}
+
+/// A rating after one game against an opponent rated `opponent`.
+pub fn updated(rating: f64, opponent: f64, won: bool) -> f64 {
+    let score = if won { 1.0 } else { 0.0 };
+    rating + K * (score - expected(rating, opponent))
+}
+
+/// A user's ratings, keyed by user id.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Ratings {
+    pub factions: BTreeMap<Faction, f64>,
+}
+
+impl Versioned for Ratings {
+    const KIND: &'static str = "ratings";
+    const MIGRATIONS: &'static [Upgrade] = &[];
+}
+
+impl Ratings {
+    pub fn get(&self, faction: Faction) -> f64 {
+        self.factions.get(&faction).copied().unwrap_or(INITIAL)
+    }
+}
+
+pub fn get(db: &Database, user_id: &str) -> Result<Ratings, MagicError> {
+    Ok(db.get(TREE, user_id)?.unwrap_or_default())
+}
+
+/// Moves everyone's rating for the faction they played, against the average
+/// rating of the other side.
+pub fn record(db: &Database, result: &GameResult) -> Result<(), MagicError> {
+    let mut before = vec![];
+    for player in &result.players {
+        let faction = player.role.faction();
+        before.push((player, faction, get(db, &player.user_id)?.get(faction)));
+    }
+
+    let mut totals: HashMap<Faction, (f64, u32)> = HashMap::new();
+    for (_, faction, rating) in &before {
+        let total = totals.entry(*faction).or_default();
+        total.0 += rating;
+        total.1 += 1;
+    }
+
+    for (player, faction, rating) in before {
+        let opponents = totals
+            .iter()
+            .filter(|(other, _)| **other != faction)
+            .fold((0.0, 0), |(sum, count), (_, (s, c))| (sum + s, count + c));
+
+        // a game with only one side in it doesn't say much about anyone
+        if opponents.1 == 0 {
+            continue;
+        }
+        let new_rating = updated(rating, opponents.0 / f64::from(opponents.1), player.won);
+
+        db.update(TREE, &player.user_id, |ratings: Option<Ratings>| {
+            let mut ratings = ratings.unwrap_or_default();
+            ratings.factions.insert(faction, new_rating);
+            Some(ratings)
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{PlayerResult, Role};
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn expected_is_symmetric() {
+        for (a, b) in [(1000.0, 1000.0), (1200.0, 800.0), (1534.5, 1490.25)] {
+            assert!(close(expected(a, b) + expected(b, a), 1.0));
+            // what one side gains, the other loses
+            let gained = updated(a, b, true) - a;
+            let lost = b - updated(b, a, false);
+            assert!(close(gained, lost));
+        }
+    }
+
+    #[test]
+    fn equal_ratings_move_by_half_of_k() {
+        assert!(close(expected(INITIAL, INITIAL), 0.5));
+        assert!(close(updated(INITIAL, INITIAL, true), INITIAL + K / 2.0));
+        assert!(close(updated(INITIAL, INITIAL, false), INITIAL - K / 2.0));
+    }
+
+    #[test]
+    fn one_sided_games_are_skipped() {
+        let db = Database::make(sled::Config::new().temporary(true).open().unwrap());
+        let villager = |user_id: &str, won| PlayerResult {
+            user_id: user_id.to_string(),
+            role: Role::Villager,
+            won,
+            banished: false,
+            times_experimented_on: 0,
+            correct_reads: 0,
+        };
+
+        record(
+            &db,
+            &GameResult {
+                game_id: "7".to_string(),
+                guild_id: "guild".to_string(),
+                channel_id: "channel".to_string(),
+                players: vec![villager("winner", true), villager("loser", false)],
+            },
+        )
+        .unwrap();
+
+        assert!(db.get::<Ratings>(TREE, "winner").unwrap().is_none());
+        assert!(db.get::<Ratings>(TREE, "loser").unwrap().is_none());
+    }
+}
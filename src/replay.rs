@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+
+use crate::discord::Discord;
+use crate::history::{self, GameSummary, LoggedEvent};
+use crate::response_types::Data;
+use crate::{Database, MagicError};
+
+/// Everything that happened in a game, for people to look back on.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Replay {
+    pub game_id: String,
+    pub summary: GameSummary,
+    pub events: Vec<LoggedEvent>,
+    /// The same thing as `events`, but for reading.
+    pub transcript: String,
+}
+
+impl Replay {
+    /// `None` if there's no such game.
+    pub fn build(db: &Database, game_id: &str) -> Result<Option<Self>, MagicError> {
+        let summary = match history::summary(db, game_id)? {
+            Some(summary) => summary,
+            None => return Ok(None),
+        };
+        let events = history::events(db, game_id)?;
+
+        let mut transcript = header(game_id, &summary);
+        for logged in &events {
+            transcript.push('\n');
+            transcript.push_str(&line(&summary, logged));
+        }
+
+        Ok(Some(Self {
+            game_id: game_id.to_string(),
+            summary,
+            events,
+            transcript,
+        }))
+    }
+
+    pub fn file_name(&self) -> String {
+        format!("game-{}.json", self.game_id)
+    }
+
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec_pretty(self).expect("could not serialize replay?")
+    }
+
+    /// Posts the end-of-game message in the game's channel, with this
+    /// attached.
+    pub async fn announce(&self, discord: &Discord) -> Result<(), MagicError> {
+        let message = Data::content(format!(
+            "game over, {}. the replay's attached, or see `/game {}`.",
+            self.summary.describe_ending(),
+            self.game_id
+        ));
+
+        discord
+            .send_file(
+                &self.summary.channel_id,
+                &message,
+                &self.file_name(),
+                self.to_json(),
+            )
+            .await
+    }
+}
+
+/// The first line of a transcript.
+pub fn header(game_id: &str, summary: &GameSummary) -> String {
+    format!(
+        "game {} in <#{}>, started by <@{}> with {} players, {}.",
+        game_id,
+        summary.channel_id,
+        summary.creator,
+        summary.players.len(),
+        summary.describe_ending()
+    )
+}
+
+/// One event in a transcript, timed from the start of the game.
+pub fn line(summary: &GameSummary, logged: &LoggedEvent) -> String {
+    let since = logged.at.saturating_sub(summary.started_at);
+
+    format!(
+        "[{:02}:{:02}:{:02}] {}",
+        since / 3600,
+        since / 60 % 60,
+        since % 60,
+        logged.event
+    )
+}
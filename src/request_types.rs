@@ -1,3 +1,6 @@
+// these mirror discord's payloads, so plenty of fields never get read.
+#![allow(dead_code)]
+
 use std::convert::TryFrom;
 
 use serde::Deserialize;
@@ -12,24 +15,35 @@ pub struct RawInteraction {
     guild_id: Option<String>,
     channel_id: Option<String>,
     member: Option<GuildMember>,
-    token: String,
+    token: Token,
     version: u8,
 }
 
+impl RawInteraction {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
 impl TryFrom<RawInteraction> for Interaction {
     type Error = crate::MagicError;
 
     fn try_from(value: RawInteraction) -> Result<Self, Self::Error> {
+        let missing =
+            |field: &str| crate::MagicError::MalformedInteraction(format!("missing {}", field));
+
         if value.interaction_type == 1 {
-            Err(crate::MagicError::GenericError)
+            Err(crate::MagicError::MalformedInteraction(
+                "pings aren't commands".to_string(),
+            ))
         } else {
             Ok(Self {
                 id: value.id,
                 interaction_type: value.interaction_type,
                 data: value.data,
-                guild_id: value.guild_id.ok_or(crate::MagicError::GenericError)?,
-                channel_id: value.channel_id.ok_or(crate::MagicError::GenericError)?,
-                member: value.member.ok_or(crate::MagicError::GenericError)?,
+                guild_id: value.guild_id.ok_or_else(|| missing("guild_id"))?,
+                channel_id: value.channel_id.ok_or_else(|| missing("channel_id"))?,
+                member: value.member.ok_or_else(|| missing("member"))?,
                 token: value.token,
                 version: value.version,
             })
@@ -47,7 +61,7 @@ pub struct Interaction {
     guild_id: String,
     channel_id: String,
     member: GuildMember,
-    token: String,
+    token: Token,
     version: u8,
 }
 
@@ -75,6 +89,17 @@ impl Interaction {
     }
 }
 
+/// Lets anyone holding it answer as us for a while, so it's kept out of logs.
+#[derive(Deserialize, Clone)]
+#[serde(transparent)]
+pub struct Token(String);
+
+impl std::fmt::Debug for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Token(..)")
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct ApplicationCommandData {
     id: String,
@@ -87,17 +112,36 @@ impl ApplicationCommandData {
         self.id
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn options(self) -> Option<Vec<ApplicationCommandDataOption>> {
         self.options
     }
+
+    /// The value of a top-level option, if it was given.
+    pub fn option(&self, name: &str) -> Option<&ApplicationCommandDataValue> {
+        self.options
+            .as_ref()?
+            .iter()
+            .find_map(|option| match option {
+                ApplicationCommandDataOption::Value {
+                    name: option_name,
+                    value,
+                } if option_name == name => Some(value),
+                _ => None,
+            })
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum ApplicationCommandDataValue {
     String(String),
-    // it can be higher, but oh well.
-    Number(i128),
+    // discord keeps these within ±2^53. this can't be an i128, untagged enums
+    // can't deserialize those.
+    Number(i64),
     Boolean(bool),
 }
 
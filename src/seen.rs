@@ -0,0 +1,96 @@
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Mutex;
+
+/// Interaction ids we've handled lately, so nothing gets handled twice. Ids
+/// only need remembering for as long as their timestamp would still be
+/// accepted, since anything older gets turned away before it's looked up.
+pub struct SeenInteractions {
+    /// In seconds.
+    remember_for: u64,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Default)]
+struct Seen {
+    ids: HashSet<String>,
+    /// When each id can be forgotten, soonest first.
+    forget: BTreeSet<(u64, String)>,
+}
+
+impl SeenInteractions {
+    pub fn new(remember_for: u64) -> Self {
+        Self {
+            remember_for,
+            seen: Mutex::default(),
+        }
+    }
+
+    /// Remembers `id`, sent at `sent`, returning whether this is the first
+    /// time we've seen it.
+    ///
+    /// A timestamp is accepted for `remember_for` either side of now, so one
+    /// from the future stays fresh for longer than one sent now. The id is
+    /// kept until its timestamp can't be accepted anymore, whichever of the
+    /// two that is.
+    pub fn first_time(&self, id: &str, sent: u64, now: u64) -> bool {
+        let mut seen = self.seen.lock().expect("seen interactions were poisoned");
+
+        while let Some((at, _)) = seen.forget.first() {
+            if *at >= now {
+                break;
+            }
+            if let Some((_, old)) = seen.forget.pop_first() {
+                seen.ids.remove(&old);
+            }
+        }
+
+        if seen.ids.insert(id.to_string()) {
+            let forget_at = sent.max(now).saturating_add(self.remember_for);
+            seen.forget.insert((forget_at, id.to_string()));
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_forgotten_once_they_would_be_stale() {
+        let seen = SeenInteractions::new(300);
+
+        assert!(seen.first_time("now", 1000, 1000));
+        assert!(!seen.first_time("now", 1000, 1000));
+        assert!(!seen.first_time("now", 1000, 1300));
+        // by now it's too old to get past the timestamp check anyway
+        assert!(seen.first_time("now", 1000, 1301));
+    }
+
+    #[test]
+    fn ids_from_the_future_are_kept_for_longer() {
+        let seen = SeenInteractions::new(300);
+
+        assert!(seen.first_time("ahead", 1300, 1000));
+        // a replay sent now would still be fresh until 1600
+        assert!(!seen.first_time("ahead", 1300, 1400));
+        assert!(!seen.first_time("ahead", 1300, 1600));
+        assert!(seen.first_time("ahead", 1300, 1601));
+    }
+
+    #[test]
+    fn forgetting_one_id_keeps_the_rest() {
+        let seen = SeenInteractions::new(300);
+
+        assert!(seen.first_time("ahead", 1300, 1000));
+        assert!(seen.first_time("behind", 900, 1000));
+        assert!(seen.first_time("later", 1200, 1200));
+
+        // "behind" goes at 1300, but the others are still fresh
+        assert!(seen.first_time("behind", 900, 1400));
+        assert!(!seen.first_time("ahead", 1300, 1400));
+        assert!(!seen.first_time("later", 1200, 1400));
+    }
+}
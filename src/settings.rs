@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+use crate::encoding::{Upgrade, Versioned};
+use crate::{Database, MagicError};
+
+pub const TREE: &str = "guild_settings";
+
+/// What a guild's admins can change about how we behave there.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GuildSettings {
+    /// How long a lobby can go without anyone joining or leaving before it's
+    /// closed, in seconds.
+    pub lobby_timeout: u64,
+}
+
+impl Default for GuildSettings {
+    fn default() -> Self {
+        Self {
+            lobby_timeout: 2 * 60 * 60,
+        }
+    }
+}
+
+impl Versioned for GuildSettings {
+    const KIND: &'static str = "guild settings";
+    const MIGRATIONS: &'static [Upgrade] = &[];
+}
+
+/// A guild's settings, or the defaults if they never changed anything.
+pub fn get(db: &Database, guild_id: &str) -> Result<GuildSettings, MagicError> {
+    Ok(db.get(TREE, guild_id)?.unwrap_or_default())
+}
+
+pub fn set(db: &Database, guild_id: &str, settings: &GuildSettings) -> Result<(), MagicError> {
+    db.put(TREE, guild_id, settings)
+}
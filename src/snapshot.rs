@@ -0,0 +1,139 @@
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use crate::export::{self, Dump};
+use crate::{Database, MagicError};
+
+const PREFIX: &str = "snapshot-";
+
+/// A directory of database dumps, named after when they were taken.
+#[derive(Clone)]
+pub struct Snapshots {
+    dir: PathBuf,
+    keep: usize,
+}
+
+impl Snapshots {
+    /// Only the newest `keep` snapshots are kept around.
+    pub fn new<P: Into<PathBuf>>(dir: P, keep: usize) -> Self {
+        Self {
+            dir: dir.into(),
+            keep,
+        }
+    }
+
+    pub fn take(&self, db: &Database) -> Result<PathBuf, MagicError> {
+        let path = self.dir.join(format!("{}{}.json", PREFIX, crate::now()));
+        write_dump(db, &path)?;
+        self.prune()?;
+
+        Ok(path)
+    }
+
+    /// Every snapshot in the directory, oldest first.
+    pub fn list(&self) -> Result<Vec<PathBuf>, MagicError> {
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut snapshots = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if let Some(taken) = taken_at(&path) {
+                snapshots.push((taken, path));
+            }
+        }
+        snapshots.sort();
+
+        Ok(snapshots.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Replaces the database with a snapshot. Whatever was there before is
+    /// saved next to the snapshots first, in case this was a mistake. See
+    /// `export::restore` for what happens if this fails partway.
+    pub fn restore(&self, db: &Database, snapshot: &Path) -> Result<PathBuf, MagicError> {
+        let file = fs::File::open(snapshot)?;
+        let dump: Dump = serde_json::from_reader(std::io::BufReader::new(file))?;
+
+        // doesn't look like a snapshot, so pruning will leave it alone
+        let backup = self.dir.join(format!("pre-restore-{}.json", crate::now()));
+        write_dump(db, &backup)?;
+
+        export::restore(db, &dump)?;
+
+        Ok(backup)
+    }
+
+    fn prune(&self) -> Result<(), MagicError> {
+        let snapshots = self.list()?;
+
+        if snapshots.len() > self.keep {
+            for old in &snapshots[..snapshots.len() - self.keep] {
+                fs::remove_file(old)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Takes a snapshot every `every`, until `stop` turns true.
+pub async fn run(
+    snapshots: Snapshots,
+    db: Database,
+    every: Duration,
+    mut stop: watch::Receiver<bool>,
+) {
+    let mut interval = tokio::time::interval(every);
+    // the first tick is immediate, and we just started up
+    interval.tick().await;
+
+    loop {
+        // never halfway through one
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = stop.wait_for(|stop| *stop) => return,
+        }
+
+        let (snapshots, db) = (snapshots.clone(), db.clone());
+        match tokio::task::spawn_blocking(move || snapshots.take(&db)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => tracing::error!(error = %e, "was not able to take a snapshot"),
+            Err(e) => tracing::error!(error = %e, "snapshot task died"),
+        }
+    }
+}
+
+fn write_dump(db: &Database, path: &Path) -> Result<(), MagicError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let dump = export::export(db)?;
+
+    // write it somewhere else first, so a crash can't leave half a snapshot.
+    // it has to be on disk before the rename, or a crash could still leave
+    // an empty file under the real name.
+    let partial = path.with_extension("partial");
+    let mut out = BufWriter::new(fs::File::create(&partial)?);
+    serde_json::to_writer(&mut out, &dump)?;
+    out.flush()?;
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(partial, path)?;
+
+    Ok(())
+}
+
+fn taken_at(path: &Path) -> Option<u64> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix(PREFIX)?
+        .strip_suffix(".json")?
+        .parse()
+        .ok()
+}
@@ -0,0 +1,503 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::database::{Lobby, LobbyTransaction, TxError};
+use crate::{export, Database, MagicError};
+
+// `MIGRATIONS[n]` takes the schema from version `n` (in `user_version`) to
+// `n + 1`. only ever add to the end of this.
+//
+// players and lobby members are kept apart on purpose: that's how the sled
+// trees store them, and the migration has to be able to copy them over as-is.
+//
+// everything else (game history, stats, settings and so on) sits in `records`
+// as the same versioned bincode sled stores, so it can't be read with SQL
+// tools; `magic db export` writes it out as JSON instead. giving each kind its
+// own columns would mean a table and a migration per record type, and
+// keeping the encoding's version upgrades working on top of both backends.
+const MIGRATIONS: &[&str] = &[
+    "
+CREATE TABLE IF NOT EXISTS lobbies (
+    id TEXT PRIMARY KEY NOT NULL,
+    creator TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS lobby_members (
+    lobby_id TEXT NOT NULL REFERENCES lobbies (id) ON DELETE CASCADE,
+    player_id TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    PRIMARY KEY (lobby_id, position)
+);
+
+CREATE TABLE IF NOT EXISTS players (
+    id TEXT PRIMARY KEY NOT NULL,
+    lobby_id TEXT NOT NULL REFERENCES lobbies (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS players_by_lobby ON players (lobby_id);
+",
+    "
+ALTER TABLE lobbies ADD COLUMN guild_id TEXT NOT NULL DEFAULT '';
+ALTER TABLE lobbies ADD COLUMN last_active INTEGER NOT NULL DEFAULT 0;
+UPDATE lobbies SET last_active = CAST(strftime('%s', 'now') AS INTEGER);
+
+-- everything that isn't lobby state, as versioned records like in sled
+CREATE TABLE records (
+    tree TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value BLOB NOT NULL,
+    PRIMARY KEY (tree, key)
+);
+",
+    "
+ALTER TABLE lobbies ADD COLUMN game_id TEXT NOT NULL DEFAULT '';
+",
+];
+
+/// A SQLite file holding the same data as the `lobbies` and `players` sled
+/// trees.
+#[derive(Clone)]
+pub struct Sqlite {
+    // rusqlite connections aren't `Sync`, and sqlite only has one writer anyways
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Sqlite {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, MagicError> {
+        let mut conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        migrate(&mut conn)?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // a handler that panicked mid-transaction leaves the mutex poisoned, but
+    // the transaction was rolled back when it unwound, so carry on
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn transaction<F, R>(&self, f: F) -> Result<R, MagicError>
+    where
+        F: Fn(&dyn LobbyTransaction) -> Result<R, TxError>,
+    {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+
+        match f(&SqliteTransaction { tx: &tx }) {
+            Ok(result) => {
+                tx.commit()?;
+                Ok(result)
+            }
+            // dropping `tx` rolls it back
+            Err(TxError::Abort(err)) => Err(err),
+            Err(TxError::Conflict) => unreachable!("the mutex serializes sqlite transactions"),
+        }
+    }
+
+    pub(crate) fn lobbies(&self) -> Result<Vec<(String, Lobby)>, MagicError> {
+        let conn = self.conn();
+        let mut statement = conn.prepare("SELECT id FROM lobbies ORDER BY id")?;
+        let ids = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // a lobby deleted between the two reads just isn't listed
+        ids.into_iter()
+            .filter_map(|id| {
+                read_lobby(&conn, &id)
+                    .transpose()
+                    .map(|lobby| Ok((id, lobby?)))
+            })
+            .collect()
+    }
+
+    pub(crate) fn players(&self) -> Result<Vec<(String, String)>, MagicError> {
+        let conn = self.conn();
+        let mut statement = conn.prepare("SELECT id, lobby_id FROM players ORDER BY id")?;
+        let players = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(players)
+    }
+
+    pub(crate) fn get_record(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>, MagicError> {
+        let conn = self.conn();
+
+        Ok(conn
+            .query_row(
+                "SELECT value FROM records WHERE tree = ?1 AND key = ?2",
+                params![tree, key],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    pub(crate) fn put_record(&self, tree: &str, key: &str, value: &[u8]) -> Result<(), MagicError> {
+        let conn = self.conn();
+        conn.execute(
+            "INSERT INTO records (tree, key, value) VALUES (?1, ?2, ?3)
+             ON CONFLICT (tree, key) DO UPDATE SET value = excluded.value",
+            params![tree, key, value],
+        )?;
+
+        Ok(())
+    }
+
+    pub(crate) fn remove_record(&self, tree: &str, key: &str) -> Result<(), MagicError> {
+        let conn = self.conn();
+        conn.execute(
+            "DELETE FROM records WHERE tree = ?1 AND key = ?2",
+            params![tree, key],
+        )?;
+
+        Ok(())
+    }
+
+    /// `f` gets the old value and returns the new one (`None` removes it).
+    pub(crate) fn update_record<F, R>(&self, tree: &str, key: &str, f: F) -> Result<R, MagicError>
+    where
+        F: FnOnce(Option<&[u8]>) -> Result<(Option<Vec<u8>>, R), MagicError>,
+    {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+
+        let old: Option<Vec<u8>> = tx
+            .query_row(
+                "SELECT value FROM records WHERE tree = ?1 AND key = ?2",
+                params![tree, key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let (new, result) = f(old.as_deref())?;
+
+        match new {
+            Some(value) => tx.execute(
+                "INSERT INTO records (tree, key, value) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (tree, key) DO UPDATE SET value = excluded.value",
+                params![tree, key, value],
+            )?,
+            None => tx.execute(
+                "DELETE FROM records WHERE tree = ?1 AND key = ?2",
+                params![tree, key],
+            )?,
+        };
+        tx.commit()?;
+
+        Ok(result)
+    }
+
+    pub(crate) fn scan_records(&self, tree: &str) -> Result<Vec<(String, Vec<u8>)>, MagicError> {
+        let conn = self.conn();
+        let mut statement =
+            conn.prepare("SELECT key, value FROM records WHERE tree = ?1 ORDER BY key")?;
+        let records = statement
+            .query_map(params![tree], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(records)
+    }
+
+    pub(crate) fn scan_records_prefix(
+        &self,
+        tree: &str,
+        prefix: &str,
+        from: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>, MagicError> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(
+            "SELECT key, value FROM records
+             WHERE tree = ?1 AND substr(key, 1, length(?2)) = ?2 AND key >= ?3
+             ORDER BY key",
+        )?;
+        let records = statement
+            .query_map(params![tree, prefix, from], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(records)
+    }
+
+    pub fn count_lobbies(&self) -> Result<usize, MagicError> {
+        let count: i64 = self
+            .conn()
+            .query_row("SELECT COUNT(*) FROM lobbies", [], |row| row.get(0))?;
+
+        Ok(count as usize)
+    }
+
+    pub(crate) fn count_records(&self, tree: &str) -> Result<usize, MagicError> {
+        let count: i64 = self.conn().query_row(
+            "SELECT COUNT(*) FROM records WHERE tree = ?1",
+            params![tree],
+            |row| row.get(0),
+        )?;
+
+        Ok(count as usize)
+    }
+
+    pub fn is_empty(&self) -> Result<bool, MagicError> {
+        let conn = self.conn();
+        let count: i64 = conn.query_row(
+            "SELECT (SELECT COUNT(*) FROM lobbies)
+                + (SELECT COUNT(*) FROM players)
+                + (SELECT COUNT(*) FROM records)",
+            [],
+            |row| row.get(0),
+        )?;
+
+        Ok(count == 0)
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), MagicError> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    if version > MIGRATIONS.len() {
+        return Err(MagicError::Decoding(format!(
+            "sqlite schema is from a newer version ({} > {})",
+            version,
+            MIGRATIONS.len()
+        )));
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", from + 1)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+fn read_lobby(conn: &Connection, lobby_id: &str) -> rusqlite::Result<Option<Lobby>> {
+    let lobby: Option<(String, String, i64, String)> = conn
+        .query_row(
+            "SELECT creator, guild_id, last_active, game_id FROM lobbies WHERE id = ?1",
+            params![lobby_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()?;
+
+    match lobby {
+        None => Ok(None),
+        Some((creator, guild_id, last_active, game_id)) => {
+            let mut statement = conn.prepare_cached(
+                "SELECT player_id FROM lobby_members WHERE lobby_id = ?1 ORDER BY position",
+            )?;
+            let players = statement
+                .query_map(params![lobby_id], |row| row.get(0))?
+                .collect::<Result<Vec<String>, _>>()?;
+
+            Ok(Some(Lobby {
+                creator,
+                players,
+                guild_id,
+                last_active: last_active as u64,
+                game_id,
+            }))
+        }
+    }
+}
+
+struct SqliteTransaction<'a> {
+    tx: &'a rusqlite::Transaction<'a>,
+}
+
+impl LobbyTransaction for SqliteTransaction<'_> {
+    fn player_lobby(&self, player_id: &str) -> Result<Option<String>, TxError> {
+        Ok(self
+            .tx
+            .query_row(
+                "SELECT lobby_id FROM players WHERE id = ?1",
+                params![player_id],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn set_player_lobby(&self, player_id: &str, lobby_id: &str) -> Result<(), TxError> {
+        self.tx.execute(
+            "INSERT INTO players (id, lobby_id) VALUES (?1, ?2)
+             ON CONFLICT (id) DO UPDATE SET lobby_id = excluded.lobby_id",
+            params![player_id, lobby_id],
+        )?;
+        Ok(())
+    }
+
+    fn remove_player(&self, player_id: &str) -> Result<(), TxError> {
+        self.tx
+            .execute("DELETE FROM players WHERE id = ?1", params![player_id])?;
+        Ok(())
+    }
+
+    fn lobby(&self, lobby_id: &str) -> Result<Option<Lobby>, TxError> {
+        Ok(read_lobby(self.tx, lobby_id)?)
+    }
+
+    fn set_lobby(&self, lobby_id: &str, lobby: &Lobby) -> Result<(), TxError> {
+        // an upsert, since `INSERT OR REPLACE` would cascade and kick everyone out
+        self.tx.execute(
+            "INSERT INTO lobbies (id, creator, guild_id, last_active, game_id)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (id) DO UPDATE SET
+                creator = excluded.creator,
+                guild_id = excluded.guild_id,
+                last_active = excluded.last_active,
+                game_id = excluded.game_id",
+            params![
+                lobby_id,
+                lobby.creator,
+                lobby.guild_id,
+                lobby.last_active as i64,
+                lobby.game_id
+            ],
+        )?;
+        self.tx.execute(
+            "DELETE FROM lobby_members WHERE lobby_id = ?1",
+            params![lobby_id],
+        )?;
+
+        let mut statement = self.tx.prepare_cached(
+            "INSERT INTO lobby_members (lobby_id, player_id, position) VALUES (?1, ?2, ?3)",
+        )?;
+        for (position, player_id) in lobby.players.iter().enumerate() {
+            statement.execute(params![lobby_id, player_id, position as i64])?;
+        }
+
+        Ok(())
+    }
+
+    fn remove_lobby(&self, lobby_id: &str) -> Result<(), TxError> {
+        self.tx
+            .execute("DELETE FROM lobbies WHERE id = ?1", params![lobby_id])?;
+        Ok(())
+    }
+
+    fn record(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>, TxError> {
+        Ok(self
+            .tx
+            .query_row(
+                "SELECT value FROM records WHERE tree = ?1 AND key = ?2",
+                params![tree, key],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn set_record(&self, tree: &str, key: &str, value: Vec<u8>) -> Result<(), TxError> {
+        self.tx.execute(
+            "INSERT INTO records (tree, key, value) VALUES (?1, ?2, ?3)
+             ON CONFLICT (tree, key) DO UPDATE SET value = excluded.value",
+            params![tree, key, value],
+        )?;
+        Ok(())
+    }
+
+    fn remove_record(&self, tree: &str, key: &str) -> Result<(), TxError> {
+        self.tx.execute(
+            "DELETE FROM records WHERE tree = ?1 AND key = ?2",
+            params![tree, key],
+        )?;
+        Ok(())
+    }
+}
+
+/// What `migrate_from_sled` did.
+pub struct Migration {
+    pub lobbies: usize,
+    pub players: usize,
+    /// Everything besides the lobby state: history, stats, settings and the
+    /// rest.
+    pub records: usize,
+    /// Players pointing at lobbies that don't exist anymore. These can't be
+    /// copied over without breaking the foreign key, so they're dropped.
+    pub dangling_players: Vec<(String, String)>,
+}
+
+/// Copies everything from a sled database into an empty sqlite one, by way of
+/// the same dump `magic db export` makes. Like any import, the lobby state
+/// goes in all at once and the rest record by record, and anything sled can't
+/// decode anymore is left behind with a warning.
+pub fn migrate_from_sled(from: &Database, to: &Sqlite) -> Result<Migration, MagicError> {
+    if !to.is_empty()? {
+        return Err(MagicError::NotEmpty);
+    }
+
+    let mut dump = export::export(from)?;
+    let dangling_players = dump
+        .players
+        .iter()
+        .filter(|(_, lobby_id)| !dump.lobbies.contains_key(*lobby_id))
+        .map(|(player_id, lobby_id)| (player_id.clone(), lobby_id.clone()))
+        .collect::<Vec<_>>();
+    for (player_id, _) in &dangling_players {
+        dump.players.remove(player_id);
+    }
+
+    export::import(&Database::make_sqlite(to.clone()), &dump)?;
+
+    Ok(Migration {
+        lobbies: dump.lobbies.len(),
+        players: dump.players.len(),
+        records: dump.records(),
+        dangling_players,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::Event;
+    use crate::{history, state, stats};
+
+    #[test]
+    fn migrating_copies_more_than_lobbies() {
+        let from = Database::make(sled::Config::new().temporary(true).open().unwrap());
+        let to = Sqlite::open(":memory:").unwrap();
+
+        from.transaction(|tx| {
+            state::emit(
+                tx,
+                "channel",
+                "7",
+                Event::Created {
+                    guild_id: "guild".to_string(),
+                    channel_id: "channel".to_string(),
+                    creator: "creator".to_string(),
+                },
+            )
+        })
+        .unwrap();
+        from.put(stats::TREE, "someone", &stats::PlayerStats::default())
+            .unwrap();
+        from.transaction(|tx| tx.set_player_lobby("lost", "gone"))
+            .unwrap();
+
+        let migration = migrate_from_sled(&from, &to).unwrap();
+        assert_eq!(migration.lobbies, 1);
+        assert_eq!(migration.players, 1);
+        assert_eq!(migration.dangling_players.len(), 1);
+
+        let db = Database::make_sqlite(to.clone());
+        assert!(history::summary(&db, "7").unwrap().is_some());
+        assert_eq!(history::events(&db, "7").unwrap().len(), 1);
+        assert!(db
+            .get::<stats::PlayerStats>(stats::TREE, "someone")
+            .unwrap()
+            .is_some());
+
+        // and it won't do it twice
+        assert!(matches!(
+            migrate_from_sled(&from, &to),
+            Err(MagicError::NotEmpty)
+        ));
+    }
+}
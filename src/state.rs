@@ -0,0 +1,234 @@
+//! Lobby state is whatever its game's events add up to. The `lobbies` and
+//! `players` trees are kept in step with the events as they're emitted, so
+//! handlers can read them cheaply, but the events are what actually happened:
+//! if `apply` turns out to be wrong, fix it and `magic db check --repair`
+//! rebuilds the trees from the events.
+
+use serde::{Deserialize, Serialize};
+
+use crate::database::{LobbyTransaction, TxError};
+use crate::encoding::{Upgrade, Versioned};
+use crate::history::{self, Event, LoggedEvent};
+use crate::{Database, Lobby, MagicError};
+
+/// The latest snapshot of each game, keyed by game id.
+pub const SNAPSHOTS_TREE: &str = "game_snapshots";
+
+/// How many events go by between snapshots.
+const SNAPSHOT_EVERY: u64 = 16;
+
+/// Bump this whenever `apply` changes, so snapshots taken with the old one
+/// aren't trusted.
+const FOLD_VERSION: u32 = 1;
+
+/// A game's state partway through, so replaying it doesn't have to start from
+/// the beginning.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    fold: u32,
+    /// How many events this has seen.
+    events: u64,
+    lobby: Option<Lobby>,
+}
+
+impl Versioned for Snapshot {
+    const KIND: &'static str = "game snapshot";
+    const MIGRATIONS: &'static [Upgrade] = &[];
+}
+
+/// The lobby after one more event. `None` means there's no lobby.
+pub fn apply(lobby: Option<Lobby>, game_id: &str, logged: &LoggedEvent) -> Option<Lobby> {
+    let mut lobby = match (&logged.event, lobby) {
+        (
+            Event::Created {
+                guild_id, creator, ..
+            },
+            _,
+        ) => {
+            return Some(Lobby {
+                creator: creator.clone(),
+                players: vec![creator.clone()],
+                guild_id: guild_id.clone(),
+                last_active: logged.at,
+                game_id: game_id.to_string(),
+            })
+        }
+        (event, _) if event.is_ending() => return None,
+        (_, None) => return None,
+        (_, Some(lobby)) => lobby,
+    };
+
+    match &logged.event {
+        Event::Joined { user_id } if !lobby.players.contains(user_id) => {
+            lobby.players.push(user_id.clone());
+        }
+        Event::Left { user_id } => lobby.players.retain(|player| player != user_id),
+        Event::Hijacked { user_id } => {
+            lobby.creator = user_id.clone();
+            if !lobby.players.contains(user_id) {
+                lobby.players.push(user_id.clone());
+            }
+        }
+        _ => {}
+    }
+    lobby.last_active = logged.at;
+
+    Some(lobby)
+}
+
+/// Makes something happen to the lobby in `lobby_id`: the event goes in its
+/// game's log, and the lobby and its players are updated to match. Returns
+/// the lobby as it is now.
+pub fn emit(
+    tx: &dyn LobbyTransaction,
+    lobby_id: &str,
+    game_id: &str,
+    event: Event,
+) -> Result<Option<Lobby>, TxError> {
+    let logged = LoggedEvent {
+        at: crate::now(),
+        event,
+    };
+    let old = tx.lobby(lobby_id)?;
+    let new = apply(old.clone(), game_id, &logged);
+
+    project(tx, lobby_id, old.as_ref(), new.as_ref())?;
+
+    if let Some(index) = history::append(tx, game_id, &logged)? {
+        let events = index + 1;
+
+        if events % SNAPSHOT_EVERY == 0 {
+            tx.put(
+                SNAPSHOTS_TREE,
+                game_id,
+                &Snapshot {
+                    fold: FOLD_VERSION,
+                    events,
+                    lobby: new.clone(),
+                },
+            )?;
+        }
+    }
+
+    Ok(new)
+}
+
+/// Writes `new` over `old` in the lobby and player trees.
+fn project(
+    tx: &dyn LobbyTransaction,
+    lobby_id: &str,
+    old: Option<&Lobby>,
+    new: Option<&Lobby>,
+) -> Result<(), TxError> {
+    // the lobby has to exist before anyone can be in it
+    if let Some(lobby) = new {
+        tx.set_lobby(lobby_id, lobby)?;
+    }
+
+    let empty = vec![];
+    let old_players = old.map_or(&empty, |lobby| &lobby.players);
+    let new_players = new.map_or(&empty, |lobby| &lobby.players);
+
+    for player in old_players {
+        // they might have been moved somewhere else by hand
+        if !new_players.contains(player) && tx.player_lobby(player)?.as_deref() == Some(lobby_id) {
+            tx.remove_player(player)?;
+        }
+    }
+    for player in new_players {
+        if !old_players.contains(player) {
+            tx.set_player_lobby(player, lobby_id)?;
+        }
+    }
+
+    if new.is_none() {
+        tx.remove_lobby(lobby_id)?;
+    }
+
+    Ok(())
+}
+
+/// What a game's lobby should look like now, from its events. Starts from
+/// the latest snapshot, if there's one we can trust.
+pub fn replay(db: &Database, game_id: &str) -> Result<Option<Lobby>, MagicError> {
+    // snapshots can always be rebuilt, so a broken one is only skipped
+    let snapshot = db
+        .get::<Snapshot>(SNAPSHOTS_TREE, game_id)
+        .unwrap_or(None)
+        .filter(|snapshot| snapshot.fold == FOLD_VERSION);
+    let (seen, lobby) = snapshot.map_or((0, None), |snapshot| (snapshot.events, snapshot.lobby));
+
+    Ok(history::events_from(db, game_id, seen)?
+        .iter()
+        .fold(lobby, |lobby, logged| apply(lobby, game_id, logged)))
+}
+
+/// Replaces the lobby in `lobby_id` with what its game's events say, from
+/// the very first one. Returns whether anything changed.
+pub fn rebuild(db: &Database, lobby_id: &str, game_id: &str) -> Result<bool, MagicError> {
+    let replayed = history::events(db, game_id)?
+        .iter()
+        .fold(None, |lobby, logged| apply(lobby, game_id, logged));
+
+    db.transaction(|tx| {
+        let current = tx.lobby(lobby_id)?;
+
+        // someone else's game is in there now
+        if current.as_ref().map(|lobby| lobby.game_id.as_str()) != Some(game_id) {
+            return Ok(false);
+        }
+
+        tx.remove_record(SNAPSHOTS_TREE, game_id)?;
+        if current == replayed {
+            return Ok(false);
+        }
+
+        project(tx, lobby_id, current.as_ref(), replayed.as_ref())?;
+        Ok(true)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::Event;
+
+    #[test]
+    fn replaying_starts_from_the_snapshot() {
+        let sled = sled::Config::new().temporary(true).open().unwrap();
+        let db = Database::make(sled.clone());
+        let emit = |event: Event| {
+            db.transaction(|tx| emit(tx, "channel", "7", event.clone()))
+                .unwrap();
+        };
+
+        emit(Event::Created {
+            guild_id: "guild".to_string(),
+            channel_id: "channel".to_string(),
+            creator: "creator".to_string(),
+        });
+        for n in 0..SNAPSHOT_EVERY {
+            let user_id = format!("player {}", n);
+            emit(Event::Joined {
+                user_id: user_id.clone(),
+            });
+            emit(Event::Left { user_id });
+        }
+        emit(Event::Joined {
+            user_id: "last".to_string(),
+        });
+
+        // if the events before the snapshot were read at all, this would fail
+        sled.open_tree(history::EVENTS_TREE)
+            .unwrap()
+            .insert("7/0000000000", &b"not an event"[..])
+            .unwrap();
+
+        let lobby = replay(&db, "7").unwrap().unwrap();
+        assert_eq!(lobby.players, vec!["creator", "last"]);
+        assert_eq!(
+            db.transaction(|tx| tx.lobby("channel")).unwrap(),
+            Some(lobby)
+        );
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::encoding::{Upgrade, Versioned};
+use crate::game::{GameResult, Role};
+use crate::{Database, MagicError};
+
+pub const TREE: &str = "stats";
+
+/// Everything we remember about a user's games, keyed by user id.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PlayerStats {
+    pub games_played: u64,
+    pub wins: BTreeMap<Role, u64>,
+    pub times_banished: u64,
+    pub times_experimented_on: u64,
+    pub correct_reads: u64,
+}
+
+impl Versioned for PlayerStats {
+    const KIND: &'static str = "stats";
+    const MIGRATIONS: &'static [Upgrade] = &[];
+}
+
+impl PlayerStats {
+    pub fn total_wins(&self) -> u64 {
+        self.wins.values().sum()
+    }
+}
+
+pub fn get(db: &Database, user_id: &str) -> Result<PlayerStats, MagicError> {
+    Ok(db.get(TREE, user_id)?.unwrap_or_default())
+}
+
+/// Adds a finished game to everyone's stats.
+pub fn record(db: &Database, result: &GameResult) -> Result<(), MagicError> {
+    for player in &result.players {
+        db.update(TREE, &player.user_id, |stats: Option<PlayerStats>| {
+            let mut stats = stats.unwrap_or_default();
+
+            stats.games_played += 1;
+            if player.won {
+                *stats.wins.entry(player.role).or_default() += 1;
+            }
+            if player.banished {
+                stats.times_banished += 1;
+            }
+            stats.times_experimented_on += player.times_experimented_on;
+            stats.correct_reads += player.correct_reads;
+
+            Some(stats)
+        })?;
+    }
+
+    Ok(())
+}
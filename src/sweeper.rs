@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use crate::discord::Discord;
+use crate::response_types::Data;
+use crate::{history, settings, state, Database, MagicError};
+
+/// A lobby that sat around for too long, and who was in it.
+pub struct Expired {
+    pub lobby_id: String,
+    pub game_id: String,
+    pub players: Vec<String>,
+}
+
+/// Closes every lobby that's been idle for longer than its guild allows,
+/// freeing up its players. Lobbies that can't be read, or whose guild's
+/// settings can't be, are skipped, so one of them doesn't keep the rest open.
+pub fn sweep(db: &Database, now: u64) -> Result<Vec<Expired>, MagicError> {
+    let mut timeouts = HashMap::new();
+    let mut expired = vec![];
+
+    for (lobby_id, lobby) in db.scan_lobbies()? {
+        let lobby = match lobby {
+            Ok(lobby) => lobby,
+            Err(e) => {
+                tracing::warn!(lobby = %lobby_id, error = %e, "skipped a lobby that can't be read");
+                continue;
+            }
+        };
+        let timeout = match timeouts.get(&lobby.guild_id) {
+            Some(timeout) => *timeout,
+            None => match settings::get(db, &lobby.guild_id) {
+                Ok(settings) => {
+                    timeouts.insert(lobby.guild_id.clone(), settings.lobby_timeout);
+                    settings.lobby_timeout
+                }
+                Err(e) => {
+                    tracing::warn!(lobby = %lobby_id, guild = %lobby.guild_id, error = %e, "skipped a lobby whose settings can't be read");
+                    continue;
+                }
+            },
+        };
+
+        if lobby.last_active.saturating_add(timeout) > now {
+            continue;
+        }
+
+        // someone might have joined since we looked
+        let closed = db.transaction(|tx| match tx.lobby(&lobby_id)? {
+            Some(lobby) if lobby.last_active.saturating_add(timeout) <= now => {
+                state::emit(tx, &lobby_id, &lobby.game_id, history::Event::Expired)?;
+                Ok(Some(lobby))
+            }
+            _ => Ok(None),
+        })?;
+
+        if let Some(closed) = closed {
+            expired.push(Expired {
+                lobby_id,
+                game_id: closed.game_id,
+                players: closed.players,
+            });
+        }
+    }
+
+    Ok(expired)
+}
+
+/// Sweeps every `every`, telling each channel when its lobby goes, until
+/// `stop` turns true.
+pub async fn run(
+    db: Database,
+    discord: Option<Discord>,
+    every: Duration,
+    mut stop: watch::Receiver<bool>,
+) {
+    let mut interval = tokio::time::interval(every);
+
+    loop {
+        // only between sweeps, so every lobby that's closed gets announced
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = stop.wait_for(|stop| *stop) => return,
+        }
+
+        let sweeping = db.clone();
+        let expired =
+            match tokio::task::spawn_blocking(move || sweep(&sweeping, crate::now())).await {
+                Ok(Ok(expired)) => expired,
+                Ok(Err(e)) => {
+                    tracing::error!(error = %e, "was not able to sweep lobbies");
+                    continue;
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "sweeper task died");
+                    continue;
+                }
+            };
+
+        for lobby in expired {
+            tracing::info!(
+                lobby = %lobby.lobby_id,
+                players = lobby.players.len(),
+                "closed idle lobby"
+            );
+
+            if let Some(discord) = &discord {
+                let message = Data::content(
+                    "this lobby sat idle for too long, so it's been closed. use /create to start another!"
+                        .to_string(),
+                );
+
+                if let Err(e) = discord.send_message(&lobby.lobby_id, &message).await {
+                    tracing::warn!(
+                        lobby = %lobby.lobby_id,
+                        error = %e,
+                        "was not able to tell the channel its lobby closed"
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unreadable_lobbies_and_settings_dont_stop_the_sweep() {
+        let sled = sled::Config::new().temporary(true).open().unwrap();
+        let db = Database::make(sled.clone());
+        let create = |channel: &str, game: &str, guild: &str| {
+            db.transaction(|tx| {
+                state::emit(
+                    tx,
+                    channel,
+                    game,
+                    history::Event::Created {
+                        guild_id: guild.to_string(),
+                        channel_id: channel.to_string(),
+                        creator: format!("{} creator", channel),
+                    },
+                )
+            })
+            .unwrap();
+        };
+
+        create("idle", "7", "guild");
+        create("unsettled", "8", "broken guild");
+        sled.open_tree("lobbies")
+            .unwrap()
+            .insert("broken", &b"not a lobby"[..])
+            .unwrap();
+        sled.open_tree(settings::TREE)
+            .unwrap()
+            // a version this build doesn't know
+            .insert("broken guild", &[0xff, b'M', 99][..])
+            .unwrap();
+
+        let expired = sweep(&db, u64::MAX).unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].lobby_id, "idle");
+    }
+}
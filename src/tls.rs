@@ -0,0 +1,192 @@
+//! Serving https ourselves, for running without a proxy in front. The
+//! certificate and key are read from PEM files, and read again whenever
+//! either file changes, so renewing them doesn't need a restart.
+
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+
+use hyper::server::accept::Accept;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio_rustls::rustls::{self, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::MagicError;
+
+// handshakes going at once, past which accepting waits. One that's done
+// still counts until hyper takes its connection
+const MAX_HANDSHAKES: usize = 64;
+// finished handshakes waiting for hyper to take them
+const PENDING_CONNECTIONS: usize = 16;
+
+pub struct Certificates {
+    cert: PathBuf,
+    key: PathBuf,
+    /// What new connections are accepted with.
+    current: RwLock<Arc<ServerConfig>>,
+    /// When each file was last changed, as of the last time they were read.
+    modified: Mutex<(SystemTime, SystemTime)>,
+}
+
+impl Certificates {
+    pub fn load(cert: PathBuf, key: PathBuf) -> Result<Self, MagicError> {
+        let modified = (modified(&cert)?, modified(&key)?);
+        let config = server_config(&cert, &key)?;
+
+        Ok(Self {
+            cert,
+            key,
+            current: RwLock::new(Arc::new(config)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    fn current(&self) -> Arc<ServerConfig> {
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Reads the files again if either changed since last time, returning
+    /// whether they did. If they don't make sense, the old ones stay.
+    pub fn reload_if_changed(&self) -> Result<bool, MagicError> {
+        let mut last = self.modified.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = (modified(&self.cert)?, modified(&self.key)?);
+        if now == *last {
+            return Ok(false);
+        }
+
+        let config = server_config(&self.cert, &self.key)?;
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(config);
+        *last = now;
+
+        Ok(true)
+    }
+}
+
+fn modified(path: &Path) -> Result<SystemTime, MagicError> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|e| MagicError::TlsError(format!("can't read {}: {}", path.display(), e)))
+}
+
+fn server_config(cert: &Path, key: &Path) -> Result<ServerConfig, MagicError> {
+    let open = |path: &Path| {
+        std::fs::File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| MagicError::TlsError(format!("can't read {}: {}", path.display(), e)))
+    };
+
+    let chain: Vec<_> = rustls_pemfile::certs(&mut open(cert)?)
+        .map_err(|e| MagicError::TlsError(format!("{} isn't PEM: {}", cert.display(), e)))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    if chain.is_empty() {
+        return Err(MagicError::TlsError(format!(
+            "{} has no certificates in it",
+            cert.display()
+        )));
+    }
+
+    let private_key = rustls_pemfile::read_all(&mut open(key)?)
+        .map_err(|e| MagicError::TlsError(format!("{} isn't PEM: {}", key.display(), e)))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            MagicError::TlsError(format!("{} has no private key in it", key.display()))
+        })?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(chain, private_key)
+        .map_err(|e| MagicError::TlsError(e.to_string()))?;
+    // hyper's only serving http/1.1 here
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+/// Connections from `listener`, once they've finished handshaking. Each
+/// handshake gets `handshake_timeout`, and a slow one doesn't hold up the
+/// rest, though only `MAX_HANDSHAKES` go at once.
+pub fn incoming(
+    listener: TcpListener,
+    certificates: Arc<Certificates>,
+    handshake_timeout: Duration,
+) -> impl Accept<Conn = TlsStream<TcpStream>, Error = std::io::Error> {
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(PENDING_CONNECTIONS);
+    let handshakes = Arc::new(Semaphore::new(MAX_HANDSHAKES));
+
+    tokio::spawn(async move {
+        loop {
+            // waiting here leaves new connections in the listen queue
+            let permit = tokio::select! {
+                permit = handshakes.clone().acquire_owned() => {
+                    permit.expect("handshakes are never closed")
+                }
+                () = sender.closed() => break,
+            };
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                // the server stopped taking connections
+                () = sender.closed() => break,
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // likely out of file descriptors, so give it a moment
+                    tracing::warn!(error = %e, "was not able to accept a connection");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+
+            let acceptor = TlsAcceptor::from(certificates.current());
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(handshake_timeout, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = sender.send(Ok(stream)).await;
+                    }
+                    Ok(Err(e)) => tracing::debug!(%peer, error = %e, "tls handshake failed"),
+                    Err(_) => tracing::debug!(%peer, "tls handshake timed out"),
+                }
+                drop(permit);
+            });
+        }
+    });
+
+    hyper::server::accept::poll_fn(move |cx| receiver.poll_recv(cx))
+}
+
+/// Checks the files every `every`, forever, switching to them when they
+/// change. Connections that are already open keep what they started with.
+pub async fn watch(certificates: Arc<Certificates>, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    // the first tick is immediate, and they were just loaded
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        match certificates.reload_if_changed() {
+            Ok(true) => tracing::info!("reloaded the tls certificate"),
+            Ok(false) => {}
+            Err(e) => tracing::error!(
+                error = %e,
+                "was not able to reload the tls certificate, keeping the old one"
+            ),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Checking that interactions really came from discord.
+
+use ring::signature::{UnparsedPublicKey, ED25519};
+use std::convert::TryInto;
+use std::fmt;
+
+/// Checks signatures against an application's public keys. Keys are decoded
+/// as they're added, so a bad one is caught with the rest of the
+/// configuration rather than on the first request.
+#[derive(Clone, Default)]
+pub struct Verifier {
+    keys: Vec<UnparsedPublicKey<[u8; 32]>>,
+}
+
+// ring's keys don't implement Debug
+impl fmt::Debug for Verifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Verifier")
+            .field("keys", &self.keys.len())
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    NotHex(hex::FromHexError),
+    WrongLength(usize),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotHex(e) => write!(f, "isn't hex: {}", e),
+            Self::WrongLength(length) => {
+                write!(f, "should be 32 bytes of hex, but one's {} bytes", length)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+impl Verifier {
+    /// Adds a hex encoded key. Any of them can sign, for rotating keys.
+    pub fn add_key(&mut self, key: &str) -> Result<(), KeyError> {
+        let bytes = hex::decode(key.trim()).map_err(KeyError::NotHex)?;
+        let bytes: [u8; 32] = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| KeyError::WrongLength(bytes.len()))?;
+
+        self.keys.push(UnparsedPublicKey::new(&ED25519, bytes));
+        Ok(())
+    }
+
+    pub fn has_keys(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Whether one of our keys signed `timestamp` followed by `body`, which
+    /// is what discord signs.
+    pub fn verify(&self, timestamp: &[u8], body: &[u8], signature: &[u8]) -> bool {
+        let mut message = Vec::with_capacity(timestamp.len() + body.len());
+        message.extend_from_slice(timestamp);
+        message.extend_from_slice(body);
+
+        self.keys
+            .iter()
+            .any(|key| key.verify(&message, signature).is_ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn key(seed: u8) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap()
+    }
+
+    fn verifier(seeds: &[u8]) -> Verifier {
+        let mut verifier = Verifier::default();
+        for &seed in seeds {
+            verifier
+                .add_key(&hex::encode(key(seed).public_key().as_ref()))
+                .unwrap();
+        }
+        verifier
+    }
+
+    #[test]
+    fn keys_have_to_be_32_bytes_of_hex() {
+        let mut verifier = Verifier::default();
+        assert!(matches!(
+            verifier.add_key("not hex"),
+            Err(KeyError::NotHex(_))
+        ));
+        assert!(matches!(
+            verifier.add_key("abcd"),
+            Err(KeyError::WrongLength(2))
+        ));
+        assert!(!verifier.has_keys());
+
+        // surrounding whitespace is fine
+        let public_key = hex::encode(key(1).public_key().as_ref());
+        verifier.add_key(&format!(" {}\n", public_key)).unwrap();
+        assert!(verifier.has_keys());
+    }
+
+    #[test]
+    fn only_our_keys_signing_exactly_this_count() {
+        let signature = key(1).sign(b"1610000000{}");
+        let signature = signature.as_ref();
+
+        assert!(verifier(&[1]).verify(b"1610000000", b"{}", signature));
+        // any of the keys will do
+        assert!(verifier(&[2, 1]).verify(b"1610000000", b"{}", signature));
+
+        assert!(!verifier(&[2]).verify(b"1610000000", b"{}", signature));
+        assert!(!verifier(&[]).verify(b"1610000000", b"{}", signature));
+        assert!(!verifier(&[1]).verify(b"1610000001", b"{}", signature));
+        assert!(!verifier(&[1]).verify(b"1610000000", b"{ }", signature));
+
+        let mut flipped = signature.to_vec();
+        flipped[0] ^= 1;
+        assert!(!verifier(&[1]).verify(b"1610000000", b"{}", &flipped));
+        assert!(!verifier(&[1]).verify(b"1610000000", b"{}", &signature[1..]));
+        assert!(!verifier(&[1]).verify(b"1610000000", b"{}", b""));
+    }
+}